@@ -0,0 +1,577 @@
+use crate::common::{Decode, Digest, Encode, Hasher};
+use crate::sorted_kv::{MerkleLookupPath, SortedKVLookup, SortedKVRangeProof};
+use crate::sparse_merkle_tree::{
+    CompressedPath, MultiProofNode, SparseMerkleTreeMultiProof,
+    SparseMerkleTreeProof, TREE_DEPTH,
+};
+use std::convert::TryFrom;
+
+/// The version of the wire format, written before every encoded value.
+pub const CODEC_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// The input continues after the value.
+    TrailingBytes,
+    UnknownVersion(u8),
+    UnknownTag(u8),
+    /// A length or index which can't fit in the input or in the value.
+    LengthOutOfRange(u64),
+    /// Bytes which are well-formed but not a valid value, e.g. a key which
+    /// `Decode` rejects.
+    Invalid(&'static str),
+}
+
+/**
+ * A type with a binary wire format: the proofs and commitments.
+ *
+ * `encode` writes the format version, `CODEC_VERSION`, followed by the
+ * value:
+ *
+ * ```text
+ *  Digest                  32 bytes
+ *  integers                little-endian, indexes as u64
+ *  byte strings            u32 length | bytes
+ *  lists                   u32 count  | items
+ *  Option<T>               0, or 1 | T
+ *  enums                   u8 tag     | fields in order
+ *  CompressedPath          u16 depth  | bitmap (32) | list of siblings
+ * ```
+ *
+ * Keys and values are written as byte strings of their `Encode` bytes.
+ * Every value has exactly one encoding: decoding rejects unknown versions
+ * and tags, lengths which run past the input or don't fit the value, and
+ * trailing bytes.
+ */
+pub trait WireFormat: Sized {
+    fn write_to(&self, out: &mut Vec<u8>);
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError>;
+}
+
+/// Encode `value`, prefixed by `CODEC_VERSION`.
+pub fn encode<T: WireFormat>(value: &T) -> Vec<u8> {
+    let mut out = vec![CODEC_VERSION];
+    value.write_to(&mut out);
+    out
+}
+
+/// Decode a value written by `encode`, which must take up all of `bytes`.
+pub fn decode<T: WireFormat>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut r = Reader { bytes };
+    match r.u8()? {
+        CODEC_VERSION => {}
+        version => return Err(DecodeError::UnknownVersion(version)),
+    }
+
+    let value = T::read_from(&mut r)?;
+    if !r.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(value)
+}
+
+/// The input of `WireFormat::read_from`.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let mut buf = [0u8; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn index(&mut self) -> Result<usize, DecodeError> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        let ix = u64::from_le_bytes(buf);
+        usize::try_from(ix).map_err(|_| DecodeError::LengthOutOfRange(ix))
+    }
+
+    // count reads the length of a list whose items take at least
+    // `min_size` bytes each, so a length which can't fit in the rest of
+    // the input is rejected before anything is allocated for it.
+    fn count(&mut self, min_size: usize) -> Result<usize, DecodeError> {
+        let n = self.u32()?;
+        match (n as usize).checked_mul(min_size) {
+            Some(size) if size <= self.bytes.len() => Ok(n as usize),
+            _ => Err(DecodeError::LengthOutOfRange(n as u64)),
+        }
+    }
+
+    fn byte_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        let n = self.count(1)?;
+        self.take(n)
+    }
+
+    fn decodable<T: Decode>(&mut self) -> Result<T, DecodeError> {
+        T::decode(self.byte_string()?)
+            .ok_or(DecodeError::Invalid("not a valid key or value"))
+    }
+
+    // entry reads an optional (key, value) pair written by write_entry.
+    fn entry<K: Decode, V: Decode>(
+        &mut self,
+    ) -> Result<Option<(K, V)>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some((self.decodable()?, self.decodable()?))),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+
+    fn list<T: WireFormat>(
+        &mut self,
+        min_size: usize,
+    ) -> Result<Vec<T>, DecodeError> {
+        let n = self.count(min_size)?;
+        (0..n).map(|_| T::read_from(self)).collect()
+    }
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("length doesn't fit in the wire format");
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+pub(crate) fn write_index(out: &mut Vec<u8>, ix: usize) {
+    out.extend_from_slice(&(ix as u64).to_le_bytes());
+}
+
+pub(crate) fn write_byte_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+// write_entry writes an optional (key, value) pair like an `Option`, with
+// the key and value as byte strings.
+fn write_entry<K: Encode, V: Encode>(
+    out: &mut Vec<u8>,
+    entry: &Option<(K, V)>,
+) {
+    match entry {
+        None => out.push(0),
+        Some((k, v)) => {
+            out.push(1);
+            write_byte_string(out, &k.encode());
+            write_byte_string(out, &v.encode());
+        }
+    }
+}
+
+fn write_list<T: WireFormat>(out: &mut Vec<u8>, items: &[T]) {
+    write_u32(out, items.len());
+    for item in items {
+        item.write_to(out);
+    }
+}
+
+impl<H: Hasher> WireFormat for Digest<H> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_ref());
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(r.take(32)?);
+        Ok(buf.into())
+    }
+}
+
+impl<T: WireFormat> WireFormat for Option<T> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.write_to(out);
+            }
+        }
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match r.u8()? {
+            0 => Ok(None),
+            1 => T::read_from(r).map(Some),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl<A: WireFormat, B: WireFormat> WireFormat for (A, B) {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        self.0.write_to(out);
+        self.1.write_to(out);
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok((A::read_from(r)?, B::read_from(r)?))
+    }
+}
+
+impl<K, V, H> WireFormat for MerkleLookupPath<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn write_to(&self, out: &mut Vec<u8>) {
+        write_byte_string(out, &self.key.encode());
+        write_byte_string(out, &self.value.encode());
+        write_list(out, &self.siblings);
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(MerkleLookupPath {
+            key: r.decodable()?,
+            value: r.decodable()?,
+            siblings: r.list(32)?,
+        })
+    }
+}
+
+impl<K, V, H> WireFormat for SortedKVLookup<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            SortedKVLookup::NotPresent {
+                next_ix,
+                prev,
+                next,
+            } => {
+                out.push(0);
+                write_index(out, *next_ix);
+                prev.write_to(out);
+                next.write_to(out);
+            }
+            SortedKVLookup::Present {
+                ix,
+                path_siblings,
+                prev,
+                next,
+            } => {
+                out.push(1);
+                write_index(out, *ix);
+                write_list(out, path_siblings);
+                prev.write_to(out);
+                next.write_to(out);
+            }
+        }
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match r.u8()? {
+            0 => Ok(SortedKVLookup::NotPresent {
+                next_ix: r.index()?,
+                prev: Option::read_from(r)?,
+                next: Option::read_from(r)?,
+            }),
+            1 => Ok(SortedKVLookup::Present {
+                ix: r.index()?,
+                path_siblings: r.list(32)?,
+                prev: Option::read_from(r)?,
+                next: Option::read_from(r)?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl<K, V, H> WireFormat for SortedKVRangeProof<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn write_to(&self, out: &mut Vec<u8>) {
+        write_index(out, self.start);
+        write_entry(out, &self.prev);
+        write_entry(out, &self.next);
+        write_list(out, &self.siblings);
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(SortedKVRangeProof {
+            start: r.index()?,
+            prev: r.entry()?,
+            next: r.entry()?,
+            siblings: r.list(2)?,
+        })
+    }
+}
+
+impl<H: Hasher> WireFormat for CompressedPath<H> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.depth() as u16).to_le_bytes());
+        out.extend_from_slice(self.bitmap());
+        write_list(out, self.stored_siblings());
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let depth = r.u16()?;
+        if depth as usize > TREE_DEPTH {
+            return Err(DecodeError::LengthOutOfRange(depth as u64));
+        }
+
+        let mut bitmap = [0u8; TREE_DEPTH / 8];
+        bitmap.copy_from_slice(r.take(TREE_DEPTH / 8)?);
+        let siblings = r.list(32)?;
+
+        CompressedPath::from_parts(depth as usize, bitmap, siblings).ok_or(
+            DecodeError::Invalid("sibling count doesn't match the bitmap"),
+        )
+    }
+}
+
+impl<H: Hasher> WireFormat for SparseMerkleTreeProof<H> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            SparseMerkleTreeProof::NotPresent { siblings, leaf } => {
+                out.push(0);
+                siblings.write_to(out);
+                leaf.write_to(out);
+            }
+            SparseMerkleTreeProof::Present { siblings } => {
+                out.push(1);
+                siblings.write_to(out);
+            }
+        }
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match r.u8()? {
+            0 => Ok(SparseMerkleTreeProof::NotPresent {
+                siblings: CompressedPath::read_from(r)?,
+                leaf: Option::read_from(r)?,
+            }),
+            1 => Ok(SparseMerkleTreeProof::Present {
+                siblings: CompressedPath::read_from(r)?,
+            }),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl<H: Hasher> WireFormat for MultiProofNode<H> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            MultiProofNode::Hash(hash) => {
+                out.push(0);
+                hash.write_to(out);
+            }
+            MultiProofNode::Empty => out.push(1),
+            MultiProofNode::Leaf(h_k, h_v) => {
+                out.push(2);
+                h_k.write_to(out);
+                h_v.write_to(out);
+            }
+            MultiProofNode::Branch => out.push(3),
+        }
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match r.u8()? {
+            0 => Ok(MultiProofNode::Hash(Digest::read_from(r)?)),
+            1 => Ok(MultiProofNode::Empty),
+            2 => Ok(MultiProofNode::Leaf(
+                Digest::read_from(r)?,
+                Digest::read_from(r)?,
+            )),
+            3 => Ok(MultiProofNode::Branch),
+            tag => Err(DecodeError::UnknownTag(tag)),
+        }
+    }
+}
+
+impl<H: Hasher> WireFormat for SparseMerkleTreeMultiProof<H> {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        write_list(out, self.nodes());
+    }
+
+    fn read_from(r: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        r.list(1).map(SparseMerkleTreeMultiProof::from_nodes)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::common::{hash_one_thing, Sha256};
+    use crate::kv_trait::AuthenticatedKV;
+    use crate::sorted_kv::tests::InsertGetRemoveOp;
+    use crate::sorted_kv::SortedKV;
+    use crate::sparse_merkle_tree::SparseMerkleTree;
+    use std::fmt::Debug;
+
+    // roundtrip checks that `value` decodes back from its encoding, and
+    // that every strict prefix of the encoding, and the encoding with a
+    // trailing byte, is rejected.
+    fn roundtrip<T: WireFormat + PartialEq + Debug>(value: &T) -> T {
+        let bytes = encode(value);
+        let decoded = decode::<T>(&bytes).unwrap();
+        assert_eq!(&decoded, value);
+
+        for cut in 0..bytes.len() {
+            assert!(decode::<T>(&bytes[..cut]).is_err());
+        }
+        let mut longer = bytes;
+        longer.push(0);
+        assert_eq!(decode::<T>(&longer), Err(DecodeError::TrailingBytes));
+
+        decoded
+    }
+
+    pub(crate) fn trees(
+        ops: Vec<InsertGetRemoveOp>,
+    ) -> (SortedKV, SparseMerkleTree, Vec<String>) {
+        let mut sorted_kv = <SortedKV>::new();
+        let mut smt = <SparseMerkleTree>::new();
+        let mut lookups = vec![];
+        for op in ops {
+            match op {
+                InsertGetRemoveOp::Insert(k, v) => {
+                    sorted_kv = sorted_kv.insert(k.clone(), v.clone());
+                    smt = smt.insert(k, v);
+                }
+                InsertGetRemoveOp::Get(k) => lookups.push(k),
+                InsertGetRemoveOp::Remove(k) => {
+                    sorted_kv = sorted_kv.remove(k.clone());
+                    smt = smt.remove(k);
+                }
+            }
+        }
+        (sorted_kv, smt, lookups)
+    }
+
+    #[quickcheck]
+    fn codec_roundtrip_proofs(ops: Vec<InsertGetRemoveOp>) {
+        let (sorted_kv, smt, lookups) = trees(ops);
+
+        let comm = roundtrip(&sorted_kv.commit());
+        for k in lookups.iter() {
+            let (val, proof) = sorted_kv.get(k.clone());
+            let proof = roundtrip(&proof);
+            <SortedKV>::check_proof(k.clone(), val, &proof, &comm).unwrap();
+        }
+        for pair in lookups.windows(2) {
+            let (entries, proof) = sorted_kv.get_range(&pair[0], &pair[1]);
+            let proof = roundtrip(&proof);
+            <SortedKV>::check_range_proof(
+                &pair[0], &pair[1], &entries, &proof, &comm,
+            )
+            .unwrap();
+        }
+
+        let comm = roundtrip(&smt.commit());
+        for k in lookups.iter() {
+            let (val, proof) = smt.get(k.clone());
+            let proof = roundtrip(&proof);
+            proof.check(k, val.as_ref(), &comm).unwrap();
+        }
+
+        let (results, proof) = smt.get_many(&lookups);
+        roundtrip(&proof).check(&lookups, &results, &comm).unwrap();
+    }
+
+    // whatever decodes, re-encodes to the same bytes
+    #[quickcheck]
+    fn codec_encoding_is_canonical(bytes: Vec<u8>) {
+        fn check<T: WireFormat>(bytes: &[u8]) {
+            if let Ok(value) = decode::<T>(bytes) {
+                assert_eq!(encode(&value), bytes);
+            }
+        }
+
+        for bytes in [bytes.clone(), [&[CODEC_VERSION][..], &bytes].concat()] {
+            check::<Digest>(&bytes);
+            check::<SortedKVLookup>(&bytes);
+            check::<SortedKVRangeProof>(&bytes);
+            check::<SparseMerkleTreeProof>(&bytes);
+            check::<SparseMerkleTreeMultiProof>(&bytes);
+        }
+    }
+
+    #[test]
+    fn codec_rejects_malformed_input() {
+        let smt = (0..10).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+        let (_, proof) = smt.get("3".to_string());
+        let bytes = encode(&proof);
+
+        let mut other_version = bytes.clone();
+        other_version[0] = CODEC_VERSION + 1;
+        assert_eq!(
+            decode::<SparseMerkleTreeProof>(&other_version),
+            Err(DecodeError::UnknownVersion(CODEC_VERSION + 1))
+        );
+
+        let mut unknown_tag = bytes.clone();
+        unknown_tag[1] = 2;
+        assert_eq!(
+            decode::<SparseMerkleTreeProof>(&unknown_tag),
+            Err(DecodeError::UnknownTag(2))
+        );
+
+        // [version, tag, depth (2), bitmap (32), sibling count (4), ..]
+        let mut deep = bytes.clone();
+        deep[2..4].copy_from_slice(&(TREE_DEPTH as u16 + 1).to_le_bytes());
+        assert_eq!(
+            decode::<SparseMerkleTreeProof>(&deep),
+            Err(DecodeError::LengthOutOfRange(TREE_DEPTH as u64 + 1))
+        );
+
+        let mut oversized = bytes.clone();
+        oversized[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            decode::<SparseMerkleTreeProof>(&oversized),
+            Err(DecodeError::LengthOutOfRange(u32::MAX as u64))
+        );
+
+        let mut bitmap_mismatch = bytes;
+        bitmap_mismatch[4] ^= 0x80;
+        assert!(matches!(
+            decode::<SparseMerkleTreeProof>(&bitmap_mismatch),
+            Err(DecodeError::Invalid(_))
+        ));
+
+        // keys must decode with `Decode`
+        let path = MerkleLookupPath::<Vec<u8>, Vec<u8>, Sha256> {
+            key: vec![0xff],
+            value: vec![],
+            siblings: vec![hash_one_thing("sib", "")],
+        };
+        let bytes = encode(&path);
+        roundtrip(&path);
+        assert_eq!(
+            decode::<MerkleLookupPath>(&bytes),
+            Err(DecodeError::Invalid("not a valid key or value"))
+        );
+    }
+}
@@ -1,24 +1,156 @@
 #![deny(warnings)]
+use digest::consts::U32;
 use sha2;
 use sha2::Digest as Sha2Digest;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::marker::PhantomData;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub struct Digest(pub digest::Output<sha2::Sha256>);
+/// A hash function producing 32-byte digests, which every commitment in
+/// this crate is generic over.
+///
+/// Implementations are marker types, so that digests (and the structures
+/// committing to them) made with different hash functions are different
+/// types and can't be mixed up.
+pub trait Hasher:
+    Clone
+    + Copy
+    + Default
+    + PartialEq
+    + Eq
+    + std::hash::Hash
+    + core::fmt::Debug
+    + Send
+    + Sync
+    + 'static
+{
+    type Engine: sha2::Digest<OutputSize = U32>;
+
+    /// The prefix of the hex form of a digest, e.g. `sha256`.
+    const NAME: &'static str;
+}
+
+/// SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    type Engine = sha2::Sha256;
+    const NAME: &'static str = "sha256";
+}
+
+/// SHA-512/256, which is SHA-512 truncated to 256 bits and usually faster
+/// than SHA-256 on 64-bit machines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Sha512_256;
+
+impl Hasher for Sha512_256 {
+    type Engine = sha2::Sha512Trunc256;
+    const NAME: &'static str = "sha512_256";
+}
+
+// the 32 bytes of a digest, whichever hash function made it
+type DigestBytes = digest::Output<sha2::Sha256>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest<H = Sha256>(pub DigestBytes, PhantomData<H>);
+
+// digests are ordered by their bytes, so that they can be used as keys of
+// a BTreeMap or sorted into a canonical order
+impl<H: Hasher> PartialOrd for Digest<H> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<H: Hasher> Ord for Digest<H> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.as_ref().cmp(other.as_ref())
+    }
+}
+
+/// Prints the digest as `sha256:<hex>`, prefixed with the name of its hash
+/// function. This is the form `FromStr` parses back.
+impl<H: Hasher> core::fmt::Display for Digest<H> {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+    ) -> std::result::Result<(), std::fmt::Error> {
+        fmt.write_str(H::NAME)?;
+        fmt.write_str(":")?;
+        fmt.write_str(&self.to_hex())
+    }
+}
+
+impl<H: Hasher> core::fmt::Debug for Digest<H> {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+    ) -> std::result::Result<(), std::fmt::Error> {
+        core::fmt::Display::fmt(self, fmt)
+    }
+}
+
+/// Why a digest couldn't be parsed from text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseDigestError {
+    /// The text names a different hash function before the `:`.
+    WrongHasher {
+        expected: &'static str,
+        found: String,
+    },
+    /// The digest has `found` characters instead of `expected`.
+    WrongLength { expected: usize, found: usize },
+    /// The character at byte offset `index` of the text isn't a digit of
+    /// the encoding.
+    InvalidCharacter { index: usize, found: char },
+}
 
-impl core::fmt::Debug for Digest {
+impl core::fmt::Display for ParseDigestError {
     fn fmt(
         &self,
         fmt: &mut std::fmt::Formatter<'_>,
     ) -> std::result::Result<(), std::fmt::Error> {
-        fmt.write_str("sha256:")?;
-        for b in self.0.iter() {
-            fmt.write_fmt(format_args!("{:02x}", b))?;
+        match self {
+            ParseDigestError::WrongHasher { expected, found } => write!(
+                fmt,
+                "expected a {} digest, found a {} digest",
+                expected, found
+            ),
+            ParseDigestError::WrongLength { expected, found } => {
+                write!(fmt, "expected {} characters, found {}", expected, found)
+            }
+            ParseDigestError::InvalidCharacter { index, found } => {
+                write!(fmt, "invalid character {:?} at {}", found, index)
+            }
         }
-        Ok(())
     }
 }
 
-impl Digest {
+impl std::error::Error for ParseDigestError {}
+
+const HEX_LEN: usize = 64;
+const BASE64_LEN: usize = 44;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// check_chars returns the first character of `s` which `valid` rejects,
+// with its byte offset shifted by `offset`
+fn check_chars(
+    s: &str,
+    offset: usize,
+    valid: impl Fn(usize, char) -> bool,
+) -> Result<(), ParseDigestError> {
+    match s.char_indices().find(|&(i, c)| !valid(i, c)) {
+        Some((index, found)) => Err(ParseDigestError::InvalidCharacter {
+            index: index + offset,
+            found,
+        }),
+        None => Ok(()),
+    }
+}
+
+impl<H> Digest<H> {
     pub fn string(self) -> String {
         self.as_ref()
             .iter()
@@ -26,31 +158,245 @@ impl Digest {
             .collect::<Vec<_>>()
             .join("")
     }
+
+    /// The digest as 64 lowercase hex digits, without the name of its hash
+    /// function.
+    pub fn to_hex(&self) -> String {
+        self.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Parse 64 hex digits, in either case.
+    pub fn from_hex(s: &str) -> Result<Self, ParseDigestError> {
+        Self::from_hex_at(s, 0)
+    }
+
+    // from_hex_at parses the hex digits `s`, found at byte offset `offset`
+    // of the text being parsed
+    fn from_hex_at(s: &str, offset: usize) -> Result<Self, ParseDigestError> {
+        check_chars(s, offset, |_, c| c.is_ascii_hexdigit())?;
+        if s.len() != HEX_LEN {
+            return Err(ParseDigestError::WrongLength {
+                expected: HEX_LEN,
+                found: s.len(),
+            });
+        }
+
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        Ok(Digest::from(bytes))
+    }
+
+    /// The digest in standard padded base64, which is 44 characters.
+    pub fn to_base64(&self) -> String {
+        let mut out = String::with_capacity(BASE64_LEN);
+        for chunk in self.as_ref().chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    let digit = (n >> (18 - 6 * i)) as usize & 63;
+                    out.push(BASE64_ALPHABET[digit] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    /// Parse the standard padded base64 encoding of a digest. Only the
+    /// encoding `to_base64` produces is accepted.
+    pub fn from_base64(s: &str) -> Result<Self, ParseDigestError> {
+        let digit =
+            |c: char| BASE64_ALPHABET.iter().position(|&a| a as char == c);
+
+        // 32 bytes are 43 digits and one `=` of padding
+        check_chars(s, 0, |i, c| {
+            digit(c).is_some() || (c == '=' && i == s.len() - 1)
+        })?;
+        if s.len() != BASE64_LEN {
+            return Err(ParseDigestError::WrongLength {
+                expected: BASE64_LEN,
+                found: s.len(),
+            });
+        }
+        // the padding must be there, and a `=` at the end of a string of the
+        // right length is at index 43, so every other character is a digit
+        let pad = BASE64_LEN - 1;
+        if s.as_bytes()[pad] != b'=' {
+            return Err(ParseDigestError::InvalidCharacter {
+                index: pad,
+                found: s[pad..].chars().next().unwrap(),
+            });
+        }
+        let digits: Vec<u32> = s[..BASE64_LEN - 1]
+            .chars()
+            .map(|c| digit(c).unwrap() as u32)
+            .collect();
+
+        // the last digit holds the last 4 bits, and 2 bits which must be
+        // zero
+        let last = BASE64_LEN - 2;
+        if digits[last] & 3 != 0 {
+            return Err(ParseDigestError::InvalidCharacter {
+                index: last,
+                found: s[last..].chars().next().unwrap(),
+            });
+        }
+
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            // byte i is bits 8i..8i+8 of the digits, 6 bits per digit
+            let bit = 8 * i;
+            let pair = digits[bit / 6] << 6 | digits[bit / 6 + 1];
+            *b = (pair >> (4 - bit % 6)) as u8;
+        }
+        Ok(Digest::from(bytes))
+    }
+}
+
+/// Parses `sha256:<hex>` as printed by `Display`, or bare hex.
+impl<H: Hasher> core::str::FromStr for Digest<H> {
+    type Err = ParseDigestError;
+
+    fn from_str(s: &str) -> Result<Self, ParseDigestError> {
+        match s.find(':') {
+            Some(colon) if &s[..colon] == H::NAME => {
+                Self::from_hex_at(&s[colon + 1..], colon + 1)
+            }
+            Some(colon) => Err(ParseDigestError::WrongHasher {
+                expected: H::NAME,
+                found: s[..colon].to_string(),
+            }),
+            None => Self::from_hex(s),
+        }
+    }
 }
 
-pub fn zero_digest() -> Digest {
-    Digest(*digest::Output::<sha2::Sha256>::from_slice(
-        &vec![0u8; sha2::Sha256::output_size()],
-    ))
+pub fn zero_digest<H>() -> Digest<H> {
+    Digest(DigestBytes::default(), PhantomData)
 }
 
-impl From<digest::Output<sha2::Sha256>> for Digest {
-    fn from(d: digest::Output<sha2::Sha256>) -> Self {
-        Self(d)
+impl<H> From<DigestBytes> for Digest<H> {
+    fn from(d: DigestBytes) -> Self {
+        Self(d, PhantomData)
     }
 }
 
-impl AsRef<[u8]> for Digest {
+impl<H> From<[u8; 32]> for Digest<H> {
+    fn from(d: [u8; 32]) -> Self {
+        Self(d.into(), PhantomData)
+    }
+}
+
+impl<H> AsRef<[u8]> for Digest<H> {
     fn as_ref(&self) -> &[u8] {
         &self.0
     }
 }
 
-pub fn hash_one_thing<T1>(label1: &str, v1: T1) -> Digest
+/// The canonical byte encoding of a key or value, which is what gets
+/// hashed into a commitment. Two values must encode to the same bytes if
+/// and only if they are equal.
+pub trait Encode {
+    fn encode(&self) -> Cow<'_, [u8]>;
+}
+
+impl Encode for str {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl Encode for String {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl Encode for [u8] {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.to_be_bytes().to_vec())
+    }
+}
+
+impl<H> Encode for Digest<H> {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_ref())
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self) -> Cow<'_, [u8]> {
+        (**self).encode()
+    }
+}
+
+/// The inverse of `Encode`, used to read keys and values back from their
+/// canonical bytes. Returns `None` if `bytes` isn't the encoding of any
+/// value.
+pub trait Decode: Sized {
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Decode for String {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+impl Decode for u64 {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(u64::from_be_bytes)
+    }
+}
+
+impl<H> Decode for Digest<H> {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        <[u8; 32]>::decode(bytes).map(Digest::from)
+    }
+}
+
+pub fn hash_one_thing<H, T1>(label1: &str, v1: T1) -> Digest<H>
 where
+    H: Hasher,
     T1: AsRef<[u8]>,
 {
-    let mut hasher = sha2::Sha256::new();
+    let mut hasher = H::Engine::new();
     hasher.update(b"hash_one_thing");
     {
         hasher.update(label1.as_bytes());
@@ -62,17 +408,18 @@ where
     hasher.finalize().into()
 }
 
-pub fn hash_two_things<T1, T2>(
+pub fn hash_two_things<H, T1, T2>(
     label1: &str,
     label2: &str,
     v1: T1,
     v2: T2,
-) -> Digest
+) -> Digest<H>
 where
+    H: Hasher,
     T1: AsRef<[u8]>,
     T2: AsRef<[u8]>,
 {
-    let mut hasher = sha2::Sha256::new();
+    let mut hasher = H::Engine::new();
     hasher.update(b"hash_two_things");
     {
         hasher.update(label1.as_bytes());
@@ -90,3 +437,134 @@ where
 
     hasher.finalize().into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(bytes: &[u8]) -> Digest {
+        hash_one_thing("test", bytes)
+    }
+
+    #[quickcheck]
+    fn digest_text_roundtrip(bytes: Vec<u8>) {
+        let d = digest(&bytes);
+
+        assert_eq!(d.to_string().parse::<Digest>(), Ok(d));
+        assert_eq!(format!("{:?}", d), d.to_string());
+        assert_eq!(d.to_hex().parse::<Digest>(), Ok(d));
+        assert_eq!(Digest::from_hex(&d.to_hex().to_uppercase()), Ok(d));
+        assert_eq!(Digest::from_base64(&d.to_base64()), Ok(d));
+
+        let other: Digest<Sha512_256> = hash_one_thing("test", &bytes);
+        assert_eq!(other.to_string().parse::<Digest<Sha512_256>>(), Ok(other));
+    }
+
+    #[quickcheck]
+    fn digest_order_is_byte_order(a: Vec<u8>, b: Vec<u8>) {
+        let (a, b) = (digest(&a), digest(&b));
+        assert_eq!(a.cmp(&b), a.as_ref().cmp(b.as_ref()));
+    }
+
+    #[test]
+    fn digest_text_known_values() {
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let d = Digest::<Sha256>::from(bytes);
+        let hex =
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert_eq!(d.to_hex(), hex);
+        assert_eq!(d.to_string(), format!("sha256:{}", hex));
+        assert_eq!(
+            d.to_base64(),
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+        );
+    }
+
+    #[test]
+    fn digest_text_rejects_malformed_input() {
+        let d = digest(b"");
+        let hex = d.to_hex();
+
+        assert_eq!(
+            format!("sha512_256:{}", hex).parse::<Digest>(),
+            Err(ParseDigestError::WrongHasher {
+                expected: "sha256",
+                found: "sha512_256".to_string(),
+            })
+        );
+        assert_eq!(
+            hex[1..].parse::<Digest>(),
+            Err(ParseDigestError::WrongLength {
+                expected: 64,
+                found: 63
+            })
+        );
+        assert_eq!(
+            format!("sha256:{}00", hex).parse::<Digest>(),
+            Err(ParseDigestError::WrongLength {
+                expected: 64,
+                found: 66
+            })
+        );
+        // offsets count the prefix
+        assert_eq!(
+            format!("sha256:{}é", &hex[1..]).parse::<Digest>(),
+            Err(ParseDigestError::InvalidCharacter {
+                index: 70,
+                found: 'é'
+            })
+        );
+        assert_eq!(
+            format!("{}g", &hex[1..]).parse::<Digest>(),
+            Err(ParseDigestError::InvalidCharacter {
+                index: 63,
+                found: 'g'
+            })
+        );
+
+        let base64 = d.to_base64();
+        assert_eq!(
+            Digest::<Sha256>::from_base64(&base64[..43]),
+            Err(ParseDigestError::WrongLength {
+                expected: 44,
+                found: 43
+            })
+        );
+        assert_eq!(
+            Digest::<Sha256>::from_base64(&format!("={}", &base64[1..])),
+            Err(ParseDigestError::InvalidCharacter {
+                index: 0,
+                found: '='
+            })
+        );
+        assert_eq!(
+            Digest::<Sha256>::from_base64(&base64.replace('=', "-")),
+            Err(ParseDigestError::InvalidCharacter {
+                index: 43,
+                found: '-'
+            })
+        );
+
+        // the padding can't be replaced by a digit
+        assert_eq!(
+            Digest::<Sha256>::from_base64(
+                "t666hfVXIulUEBTSF1IqsabwxwNpBa0k4kmYUWuQ7tcA"
+            ),
+            Err(ParseDigestError::InvalidCharacter {
+                index: 43,
+                found: 'A'
+            })
+        );
+
+        // the 2 unused bits of the last digit must be zero
+        let mut last = base64.into_bytes();
+        last[42] = if last[42] == b'B' { b'C' } else { b'B' };
+        assert!(matches!(
+            Digest::<Sha256>::from_base64(std::str::from_utf8(&last).unwrap()),
+            Err(ParseDigestError::InvalidCharacter { index: 42, .. })
+        ));
+    }
+}
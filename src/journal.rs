@@ -0,0 +1,660 @@
+use crate::common::{hash_one_thing, Decode, Digest, Encode, Hasher, Sha256};
+use crate::node_store::{invalid_data, NodeStore};
+use crate::sparse_merkle_tree::{BatchOp, SparseMerkleTreeProof};
+use crate::stored_smt::StoredSparseMerkleTree;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// An entry of a `Journal`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JournalEntry<K = String, V = String, H: Hasher = Sha256> {
+    /// An update, with the root of the tree after it was applied.
+    Update { op: BatchOp<K, V>, root: Digest<H> },
+    /// A root whose nodes were all durable when it was logged, so the tree
+    /// can be reopened from it without the updates before it.
+    Checkpoint { root: Digest<H> },
+}
+
+impl<K, V, H: Hasher> JournalEntry<K, V, H> {
+    pub fn root(&self) -> Digest<H> {
+        match self {
+            JournalEntry::Update { root, .. }
+            | JournalEntry::Checkpoint { root } => *root,
+        }
+    }
+}
+
+// a record read back from a journal: (entry, length of the record)
+type Record<K, V, H> = (JournalEntry<K, V, H>, u64);
+
+// a journal which was opened, with every entry read back from it
+type Opened<K, V, H> = (Journal<K, V, H>, Vec<JournalEntry<K, V, H>>);
+
+const JOURNAL_MAGIC: &[u8; 8] = b"SMTWAL01";
+const INSERT_TAG: u8 = 0;
+const REMOVE_TAG: u8 = 1;
+const CHECKPOINT_TAG: u8 = 2;
+
+/**
+ * A write-ahead journal of the updates made to a tree.
+ *
+ * The file is an 8-byte magic header followed by one record per entry:
+ *
+ * ```text
+ *  payload len (u32 LE) | checksum (32) | payload
+ * ```
+ *
+ * where the payload is the root of the entry, a tag, and for an update the
+ * length-prefixed encoding of the key (and value, for an insert). Every
+ * record is synced before `append` returns, so a crash can only cut off or
+ * tear the last record. On open, a last record which is cut off or fails
+ * its checksum is dropped. A record which fails its checksum with more of
+ * the file after it can't have been torn by a crash, so the journal is
+ * corrupt and `open` fails with `InvalidData` instead of dropping the
+ * records after it.
+ *
+ * `checkpoint` replaces the whole journal with a single checkpoint, by
+ * renaming a new file over it, so a crash leaves either the old or the new
+ * journal behind.
+ */
+pub struct Journal<K = String, V = String, H: Hasher = Sha256> {
+    file: File,
+    path: PathBuf,
+    len: u64,
+    entries: PhantomData<JournalEntry<K, V, H>>,
+}
+
+impl<K, V, H: Hasher> fmt::Debug for Journal<K, V, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("file", &self.file)
+            .field("path", &self.path)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+fn checksum(payload: &[u8]) -> Digest {
+    hash_one_thing("journal_record", payload)
+}
+
+fn encode_entry<K: Encode, V: Encode, H: Hasher>(
+    entry: &JournalEntry<K, V, H>,
+) -> Vec<u8> {
+    fn push_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+        payload.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        payload.extend_from_slice(bytes);
+    }
+
+    let mut payload = entry.root().as_ref().to_vec();
+    match entry {
+        JournalEntry::Update {
+            op: BatchOp::Insert(k, v),
+            ..
+        } => {
+            payload.push(INSERT_TAG);
+            push_bytes(&mut payload, &k.encode());
+            push_bytes(&mut payload, &v.encode());
+        }
+        JournalEntry::Update {
+            op: BatchOp::Remove(k),
+            ..
+        } => {
+            payload.push(REMOVE_TAG);
+            push_bytes(&mut payload, &k.encode());
+        }
+        JournalEntry::Checkpoint { .. } => payload.push(CHECKPOINT_TAG),
+    }
+    payload
+}
+
+fn decode_entry<K: Decode, V: Decode, H: Hasher>(
+    mut payload: &[u8],
+) -> Option<JournalEntry<K, V, H>> {
+    fn take<'a>(payload: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if payload.len() < n {
+            return None;
+        }
+        let (head, tail) = payload.split_at(n);
+        *payload = tail;
+        Some(head)
+    }
+    fn take_decoded<T: Decode>(payload: &mut &[u8]) -> Option<T> {
+        let mut len = [0u8; 8];
+        len.copy_from_slice(take(payload, 8)?);
+        let len = usize::try_from(u64::from_le_bytes(len)).ok()?;
+        T::decode(take(payload, len)?)
+    }
+
+    let mut root = [0u8; 32];
+    root.copy_from_slice(take(&mut payload, 32)?);
+    let root = root.into();
+    let entry = match take(&mut payload, 1)?[0] {
+        INSERT_TAG => JournalEntry::Update {
+            op: BatchOp::Insert(
+                take_decoded(&mut payload)?,
+                take_decoded(&mut payload)?,
+            ),
+            root,
+        },
+        REMOVE_TAG => JournalEntry::Update {
+            op: BatchOp::Remove(take_decoded(&mut payload)?),
+            root,
+        },
+        CHECKPOINT_TAG => JournalEntry::Checkpoint { root },
+        _ => return None,
+    };
+
+    if !payload.is_empty() {
+        return None;
+    }
+    Some(entry)
+}
+
+// sync_dir makes a rename in the directory holding `path` durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+impl<K, V, H> Journal<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    /// Open the journal at `path`, creating it if it doesn't exist, and
+    /// return it along with every entry which was fully written.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Opened<K, V, H>> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut magic = vec![];
+        reader
+            .by_ref()
+            .take(JOURNAL_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if !JOURNAL_MAGIC.starts_with(&magic) {
+            return Err(invalid_data("not a journal"));
+        }
+
+        // a header which was cut off is written again from the start
+        let mut entries = vec![];
+        let mut len = 0;
+        if magic.len() == JOURNAL_MAGIC.len() {
+            len = magic.len() as u64;
+            while let Some((entry, record_len)) =
+                Self::read_record(&mut reader, file_len - len)?
+            {
+                entries.push(entry);
+                len += record_len;
+            }
+        }
+        drop(reader);
+
+        if len < file_len {
+            file.set_len(len)?;
+        }
+        if len == 0 {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(JOURNAL_MAGIC)?;
+            file.sync_data()?;
+            len = JOURNAL_MAGIC.len() as u64;
+        }
+
+        let journal = Journal {
+            file,
+            path,
+            len,
+            entries: PhantomData,
+        };
+        Ok((journal, entries))
+    }
+
+    // read_record reads the next record, returning its entry and length, or
+    // `None` if it is cut off or is the last record and fails its checksum.
+    // `remaining` is the number of bytes left in the file.
+    fn read_record(
+        reader: &mut BufReader<&File>,
+        remaining: u64,
+    ) -> io::Result<Option<Record<K, V, H>>> {
+        let mut header = [0u8; 4 + 32];
+        if remaining < header.len() as u64 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut header)?;
+
+        let mut payload_len = [0u8; 4];
+        payload_len.copy_from_slice(&header[..4]);
+        let payload_len = u32::from_le_bytes(payload_len) as u64;
+        let record_len = header.len() as u64 + payload_len;
+        if record_len > remaining {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; payload_len as usize];
+        reader.read_exact(&mut payload)?;
+        if checksum(&payload).as_ref() != &header[4..] {
+            if record_len < remaining {
+                return Err(invalid_data("corrupt journal record"));
+            }
+            return Ok(None);
+        }
+
+        match decode_entry(&payload) {
+            Some(entry) => Ok(Some((entry, record_len))),
+            None => Err(invalid_data("malformed journal entry")),
+        }
+    }
+
+    // record encodes `entry` as a record.
+    fn record(entry: &JournalEntry<K, V, H>) -> io::Result<Vec<u8>> {
+        let payload = encode_entry(entry);
+        let payload_len = u32::try_from(payload.len())
+            .map_err(|_| invalid_data("journal entry too large"))?;
+
+        let mut record = payload_len.to_le_bytes().to_vec();
+        record.extend_from_slice(checksum(&payload).as_ref());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Append `entry`, returning once it is durable.
+    pub fn append(&mut self, entry: &JournalEntry<K, V, H>) -> io::Result<()> {
+        let record = Self::record(entry)?;
+
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.len += record.len() as u64;
+
+        Ok(())
+    }
+
+    /// Replace every entry with a checkpoint of `root`, returning once the
+    /// new journal is durable.
+    pub fn checkpoint(&mut self, root: Digest<H>) -> io::Result<()> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        bytes.extend(Self::record(&JournalEntry::Checkpoint { root })?);
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+
+        self.file = file;
+        self.len = bytes.len() as u64;
+
+        Ok(())
+    }
+}
+
+/**
+ * A `JournaledSparseMerkleTree` is a `StoredSparseMerkleTree` which
+ * records every update in a `Journal`.
+ *
+ * An update is committed once the nodes it wrote and then its journal
+ * entry are durable. Nodes are content-addressed and never overwritten, so
+ * nodes written by an update which was never committed are just
+ * unreachable.
+ *
+ * `checkpoint` logs the current root and compacts the journal down to it.
+ * On open, the tree is reopened from the store at the last checkpoint, and
+ * only the updates after it are replayed, checking the root after each one
+ * against the one it logged. So the tree comes back at the last committed
+ * root no matter where a crash cut the journal off.
+ */
+pub struct JournaledSparseMerkleTree<S, K = String, V = String, H = Sha256>
+where
+    K: Clone,
+    V: Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H>,
+{
+    tree: StoredSparseMerkleTree<S, K, V, H>,
+    journal: Journal<K, V, H>,
+}
+
+impl<S, K, V, H> fmt::Debug for JournaledSparseMerkleTree<S, K, V, H>
+where
+    K: Clone,
+    V: Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H>,
+    StoredSparseMerkleTree<S, K, V, H>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JournaledSparseMerkleTree")
+            .field("tree", &self.tree)
+            .field("journal", &self.journal)
+            .finish()
+    }
+}
+
+impl<S, K, V, H> JournaledSparseMerkleTree<S, K, V, H>
+where
+    K: Encode + Decode + Clone,
+    V: Encode + Decode + Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H, Ref = Digest<H>>,
+{
+    /// Open the tree journaled at `path`, with its nodes in `store`.
+    pub fn open(store: S, path: impl AsRef<Path>) -> io::Result<Self> {
+        let (journal, entries) = Journal::open(path)?;
+
+        let start = entries.iter().rposition(|entry| {
+            matches!(entry, JournalEntry::Checkpoint { .. })
+        });
+        let mut tree = match start {
+            Some(i) => StoredSparseMerkleTree::open(store, entries[i].root())?,
+            None => StoredSparseMerkleTree::with_store(store),
+        };
+
+        let replay = entries.into_iter().skip(start.map_or(0, |i| i + 1));
+        for entry in replay {
+            if let JournalEntry::Update { op, root } = entry {
+                match op {
+                    BatchOp::Insert(k, v) => tree.try_insert(k, v)?,
+                    BatchOp::Remove(k) => tree.try_remove(&k)?,
+                }
+                if tree.commit() != root {
+                    return Err(invalid_data("journal root mismatch"));
+                }
+            }
+        }
+
+        Ok(JournaledSparseMerkleTree { tree, journal })
+    }
+
+    /// Log a checkpoint of the current root, once every node is durable,
+    /// and compact the journal down to it, so that reopening the tree
+    /// doesn't replay any update made so far.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.tree.store_mut().sync()?;
+        self.journal.checkpoint(self.tree.commit())
+    }
+
+    pub fn commit(&self) -> Digest<H> {
+        self.tree.commit()
+    }
+
+    pub fn tree(&self) -> &StoredSparseMerkleTree<S, K, V, H> {
+        &self.tree
+    }
+
+    pub fn get(
+        &self,
+        key: &K,
+    ) -> io::Result<(Option<V>, SparseMerkleTreeProof<H>)> {
+        self.tree.try_get(key)
+    }
+
+    /// Insert `key`, returning the new root once the update is committed.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Digest<H>> {
+        self.apply(BatchOp::Insert(key, value))
+    }
+
+    /// Remove `key`, returning the new root once the update is committed.
+    pub fn remove(&mut self, key: K) -> io::Result<Digest<H>> {
+        self.apply(BatchOp::Remove(key))
+    }
+
+    fn apply(&mut self, op: BatchOp<K, V>) -> io::Result<Digest<H>> {
+        let (old_root, old_len) = (*self.tree.root_ref(), self.tree.len());
+        let res = match &op {
+            BatchOp::Insert(k, v) => self.tree.try_insert(k.clone(), v.clone()),
+            BatchOp::Remove(k) => self.tree.try_remove(k),
+        };
+
+        // an update which couldn't be journaled isn't committed, so the
+        // tree goes back to the last committed root. Its nodes are made
+        // durable first, so every root in the journal can be reopened.
+        let root = self.tree.commit();
+        let entry = JournalEntry::Update { op, root };
+        if let Err(e) = res
+            .and_then(|_| self.tree.store_mut().sync())
+            .and_then(|_| self.journal.append(&entry))
+        {
+            self.tree.set_root(old_root, old_len);
+            return Err(e);
+        }
+
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sha512_256;
+    use crate::node_store::tests::TempPath;
+    use crate::node_store::FileNodeStore;
+    use crate::sorted_kv::tests::binary_entries;
+    use crate::sparse_merkle_tree::{empty_hash, TREE_DEPTH};
+
+    fn ops() -> Vec<BatchOp> {
+        let mut ops = vec![];
+        for i in 0..6 {
+            ops.push(BatchOp::Insert(i.to_string(), format!("v{}", i)));
+        }
+        ops.push(BatchOp::Remove("2".to_string()));
+        ops.push(BatchOp::Insert("0".to_string(), "".to_string()));
+        ops.push(BatchOp::Remove("missing".to_string()));
+        ops
+    }
+
+    #[test]
+    fn journal_recovers_from_any_cut() {
+        let nodes_path = TempPath::new("journal-nodes");
+        let path = TempPath::new("journal");
+
+        // (journal length, root) after every committed update, starting
+        // from the checkpoint the journal is compacted to
+        let mut commits = vec![(0, empty_hash(TREE_DEPTH))];
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        let mut tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        for (i, op) in ops().into_iter().enumerate() {
+            if i == 3 {
+                tree.checkpoint().unwrap();
+                let len = std::fs::metadata(&path.0).unwrap().len();
+                commits.push((len, tree.commit()));
+            }
+            let root = match op {
+                BatchOp::Insert(k, v) => tree.insert(k, v).unwrap(),
+                BatchOp::Remove(k) => tree.remove(k).unwrap(),
+            };
+            commits.push((std::fs::metadata(&path.0).unwrap().len(), root));
+        }
+        drop(tree);
+        let journal = std::fs::read(&path.0).unwrap();
+
+        for cut in 0..=journal.len() {
+            let cut_nodes_path = TempPath::new("journal-cut-nodes");
+            let cut_path = TempPath::new("journal-cut");
+            std::fs::copy(&nodes_path.0, &cut_nodes_path.0).unwrap();
+            std::fs::write(&cut_path.0, &journal[..cut]).unwrap();
+            let open = || {
+                let store = <FileNodeStore>::open(&cut_nodes_path.0).unwrap();
+                JournaledSparseMerkleTree::open(store, &cut_path.0).unwrap()
+            };
+
+            let expected = commits
+                .iter()
+                .rev()
+                .find(|(len, _)| *len <= cut as u64)
+                .unwrap()
+                .1;
+            let mut tree = open();
+            assert_eq!(tree.commit(), expected);
+
+            // the journal can be appended to after recovery
+            let root =
+                tree.insert("after".to_string(), "cut".to_string()).unwrap();
+            drop(tree);
+            let tree = open();
+            assert_eq!(tree.commit(), root);
+            let (val, proof) = tree.get(&"after".to_string()).unwrap();
+            assert_eq!(val.as_deref(), Some("cut"));
+            proof.check("after", val.as_ref(), &root).unwrap();
+        }
+    }
+
+    #[test]
+    fn journal_checkpoint_compacts() {
+        let nodes_path = TempPath::new("journal-checkpoint-nodes");
+        let path = TempPath::new("journal-checkpoint");
+
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        let mut tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        for op in ops() {
+            match op {
+                BatchOp::Insert(k, v) => tree.insert(k, v).unwrap(),
+                BatchOp::Remove(k) => tree.remove(k).unwrap(),
+            };
+        }
+        tree.checkpoint().unwrap();
+        let checkpoint = tree.commit();
+        let root = tree.insert("6".to_string(), "v6".to_string()).unwrap();
+        let len = tree.tree().len();
+        drop(tree);
+
+        // only the checkpoint and the update after it are left
+        let (_, entries) = <Journal>::open(&path.0).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], JournalEntry::Checkpoint { root: checkpoint });
+
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        let tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        assert_eq!(tree.commit(), root);
+        assert_eq!(tree.tree().len(), len);
+        assert_eq!(
+            tree.get(&"1".to_string()).unwrap().0.as_deref(),
+            Some("v1")
+        );
+
+        // the tree is reopened from the store at the checkpoint, so the
+        // nodes before it can't be rebuilt from the journal
+        let empty_nodes_path = TempPath::new("journal-checkpoint-empty");
+        let store = <FileNodeStore>::open(&empty_nodes_path.0).unwrap();
+        let err = JournaledSparseMerkleTree::open(store, &path.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn journal_with_file_store() {
+        let nodes_path = TempPath::new("journal-nodes");
+        let path = TempPath::new("journal-file");
+
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        let mut tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        for op in ops() {
+            match op {
+                BatchOp::Insert(k, v) => tree.insert(k, v).unwrap(),
+                BatchOp::Remove(k) => tree.remove(k).unwrap(),
+            };
+        }
+        let root = tree.commit();
+        drop(tree);
+
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        let tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        assert_eq!(tree.commit(), root);
+        assert_eq!(
+            tree.get(&"1".to_string()).unwrap().0.as_deref(),
+            Some("v1")
+        );
+        assert_eq!(tree.get(&"2".to_string()).unwrap().0, None);
+    }
+
+    #[test]
+    fn journal_with_binary_keys_and_other_hasher() {
+        type Store = FileNodeStore<Vec<u8>, Vec<u8>, Sha512_256>;
+        let nodes_path = TempPath::new("journal-binary-nodes");
+        let path = TempPath::new("journal-binary");
+
+        let store = Store::open(&nodes_path.0).unwrap();
+        let mut tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        for (k, v) in binary_entries() {
+            tree.insert(k, v).unwrap();
+        }
+        let root = tree.remove(vec![0xfe]).unwrap();
+        drop(tree);
+
+        let store = Store::open(&nodes_path.0).unwrap();
+        let tree = JournaledSparseMerkleTree::open(store, &path.0).unwrap();
+        assert_eq!(tree.commit(), root);
+        let (val, proof) = tree.get(&vec![0xff]).unwrap();
+        assert_eq!(val, Some(vec![0x80, 0]));
+        proof.check(&[0xff][..], val.as_deref(), &root).unwrap();
+        assert_eq!(tree.get(&vec![0xfe]).unwrap().0, None);
+    }
+
+    #[test]
+    fn journal_drops_only_a_torn_last_record() {
+        let path = TempPath::new("journal-torn");
+        let (mut journal, _) = <Journal>::open(&path.0).unwrap();
+        let root = empty_hash(TREE_DEPTH);
+        for op in ops().into_iter().take(3) {
+            journal.append(&JournalEntry::Update { op, root }).unwrap();
+        }
+        drop(journal);
+        let bytes = std::fs::read(&path.0).unwrap();
+
+        // a bad checksum in the last record is a torn write, and is dropped
+        let mut torn = bytes.clone();
+        *torn.last_mut().unwrap() ^= 1;
+        std::fs::write(&path.0, &torn).unwrap();
+        let (_, entries) = <Journal>::open(&path.0).unwrap();
+        assert_eq!(entries.len(), 2);
+        let len = std::fs::metadata(&path.0).unwrap().len();
+        assert!(len < bytes.len() as u64);
+
+        // in the first record, the entries after it would be lost, so the
+        // journal is left as it is
+        let mut corrupt = bytes.clone();
+        corrupt[JOURNAL_MAGIC.len() + 4 + 32] ^= 1;
+        std::fs::write(&path.0, &corrupt).unwrap();
+        let err = <Journal>::open(&path.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path.0).unwrap(), corrupt);
+    }
+
+    #[test]
+    fn journal_rejects_wrong_root() {
+        let path = TempPath::new("journal-wrong-root");
+        let (mut journal, entries) = <Journal>::open(&path.0).unwrap();
+        assert!(entries.is_empty());
+
+        let op = BatchOp::Insert("0".to_string(), "a".to_string());
+        let root = empty_hash(TREE_DEPTH);
+        journal.append(&JournalEntry::Update { op, root }).unwrap();
+        drop(journal);
+
+        let (_, entries) = <Journal>::open(&path.0).unwrap();
+        assert_eq!(entries.len(), 1);
+        let nodes_path = TempPath::new("journal-wrong-root-nodes");
+        let store = <FileNodeStore>::open(&nodes_path.0).unwrap();
+        assert!(JournaledSparseMerkleTree::open(store, &path.0).is_err());
+    }
+}
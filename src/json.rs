@@ -0,0 +1,601 @@
+use crate::codec::{
+    self, write_byte_string, write_index, write_u32, DecodeError, WireFormat,
+    CODEC_VERSION,
+};
+use crate::common::{Decode, Digest, Encode, Hasher};
+use crate::sorted_kv::{MerkleLookupPath, SortedKVLookup, SortedKVRangeProof};
+use crate::sparse_merkle_tree::{
+    CompressedPath, MultiProofNode, SparseMerkleTreeMultiProof,
+    SparseMerkleTreeProof,
+};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    /// The input isn't JSON.
+    Syntax(String),
+    /// The JSON doesn't have the shape of the value.
+    Shape(&'static str),
+    /// The JSON has the right shape, but the value it describes is
+    /// rejected by the binary codec.
+    Decode(DecodeError),
+}
+
+/**
+ * A type with a JSON encoding, for debugging and for clients which can't
+ * read the binary wire format.
+ *
+ * Digests and byte strings (keys and values) are hex, indexes and depths
+ * are numbers, `Option`s are `null` or the value, and enum variants are
+ * objects with a single field named after the variant, e.g.
+ *
+ * ```text
+ *  {"Present": {"siblings": {"depth": 1, "bitmap": "80..", "siblings": [".."]}}}
+ * ```
+ *
+ * Decoding translates the JSON into the binary wire format and decodes
+ * that, so it accepts exactly the values `codec::decode` accepts.
+ */
+pub trait JsonFormat: WireFormat {
+    fn to_json(&self) -> Value;
+
+    // json_to_wire appends the binary encoding of the value described by
+    // `json`, checking only the shape of the JSON.
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError>;
+}
+
+pub fn to_json<T: JsonFormat>(value: &T) -> String {
+    value.to_json().to_string()
+}
+
+pub fn from_json<T: JsonFormat>(json: &str) -> Result<T, JsonError> {
+    let json: Value = serde_json::from_str(json)
+        .map_err(|e| JsonError::Syntax(e.to_string()))?;
+
+    let mut wire = vec![CODEC_VERSION];
+    T::json_to_wire(&json, &mut wire)?;
+    codec::decode(&wire).map_err(JsonError::Decode)
+}
+
+fn hex(bytes: &[u8]) -> Value {
+    Value::String(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn unhex(json: &Value) -> Result<Vec<u8>, JsonError> {
+    let s = json
+        .as_str()
+        .ok_or(JsonError::Shape("expected a hex string"))?;
+    if s.len() % 2 != 0 {
+        return Err(JsonError::Shape("hex string of odd length"));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or(JsonError::Shape("invalid hex digit"))
+        })
+        .collect()
+}
+
+fn number(json: &Value) -> Result<u64, JsonError> {
+    json.as_u64().ok_or(JsonError::Shape("expected a number"))
+}
+
+// index reads a position in a list, rejecting one that doesn't fit in a
+// `usize` just as the binary codec does.
+fn index(json: &Value) -> Result<usize, JsonError> {
+    let ix = number(json)?;
+    usize::try_from(ix)
+        .map_err(|_| JsonError::Decode(DecodeError::LengthOutOfRange(ix)))
+}
+
+// fields returns the fields of the object `json`, which must have exactly
+// the fields in `names`, in the order of `names`.
+fn fields<'a, const N: usize>(
+    json: &'a Value,
+    names: [&str; N],
+) -> Result<[&'a Value; N], JsonError> {
+    let obj = json
+        .as_object()
+        .ok_or(JsonError::Shape("expected an object"))?;
+    if obj.len() != N {
+        return Err(JsonError::Shape("unexpected field"));
+    }
+
+    let mut ret = [&Value::Null; N];
+    for (field, name) in ret.iter_mut().zip(names.iter()) {
+        *field = obj.get(*name).ok_or(JsonError::Shape("missing field"))?;
+    }
+    Ok(ret)
+}
+
+// variant splits an enum variant into its name and its contents.
+fn variant(json: &Value) -> Result<(&str, &Value), JsonError> {
+    match json.as_object() {
+        Some(obj) if obj.len() == 1 => {
+            let (name, contents) = obj.iter().next().unwrap();
+            Ok((name.as_str(), contents))
+        }
+        _ => Err(JsonError::Shape("expected an object with one variant")),
+    }
+}
+
+fn list_to_json<T: JsonFormat>(items: &[T]) -> Value {
+    Value::Array(items.iter().map(JsonFormat::to_json).collect())
+}
+
+fn list_to_wire<T: JsonFormat>(
+    json: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), JsonError> {
+    let items = json.as_array().ok_or(JsonError::Shape("expected a list"))?;
+    write_u32(out, items.len());
+    items.iter().try_for_each(|item| T::json_to_wire(item, out))
+}
+
+impl<H: Hasher> JsonFormat for Digest<H> {
+    fn to_json(&self) -> Value {
+        Value::String(self.to_hex())
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let bytes = unhex(json)?;
+        if bytes.len() != 32 {
+            return Err(JsonError::Shape("digest must be 32 bytes"));
+        }
+        out.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+impl<T: JsonFormat> JsonFormat for Option<T> {
+    fn to_json(&self) -> Value {
+        self.as_ref().map_or(Value::Null, JsonFormat::to_json)
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        if json.is_null() {
+            out.push(0);
+            return Ok(());
+        }
+        out.push(1);
+        T::json_to_wire(json, out)
+    }
+}
+
+impl<A: JsonFormat, B: JsonFormat> JsonFormat for (A, B) {
+    fn to_json(&self) -> Value {
+        json!([self.0.to_json(), self.1.to_json()])
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        match json.as_array().map(Vec::as_slice) {
+            Some([a, b]) => {
+                A::json_to_wire(a, out)?;
+                B::json_to_wire(b, out)
+            }
+            _ => Err(JsonError::Shape("expected a pair")),
+        }
+    }
+}
+
+impl<K, V, H> JsonFormat for MerkleLookupPath<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn to_json(&self) -> Value {
+        json!({
+            "key": hex(&self.key.encode()),
+            "value": hex(&self.value.encode()),
+            "siblings": list_to_json(&self.siblings),
+        })
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let [key, value, siblings] =
+            fields(json, ["key", "value", "siblings"])?;
+        write_byte_string(out, &unhex(key)?);
+        write_byte_string(out, &unhex(value)?);
+        list_to_wire::<Digest<H>>(siblings, out)
+    }
+}
+
+impl<K, V, H> JsonFormat for SortedKVLookup<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn to_json(&self) -> Value {
+        match self {
+            SortedKVLookup::NotPresent {
+                next_ix,
+                prev,
+                next,
+            } => json!({
+                "NotPresent": {
+                    "next_ix": next_ix,
+                    "prev": prev.to_json(),
+                    "next": next.to_json(),
+                }
+            }),
+            SortedKVLookup::Present {
+                ix,
+                path_siblings,
+                prev,
+                next,
+            } => json!({
+                "Present": {
+                    "ix": ix,
+                    "path_siblings": list_to_json(path_siblings),
+                    "prev": prev.to_json(),
+                    "next": next.to_json(),
+                }
+            }),
+        }
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        type Path<K, V, H> = Option<MerkleLookupPath<K, V, H>>;
+
+        match variant(json)? {
+            ("NotPresent", json) => {
+                let [next_ix, prev, next] =
+                    fields(json, ["next_ix", "prev", "next"])?;
+                out.push(0);
+                write_index(out, index(next_ix)?);
+                Path::<K, V, H>::json_to_wire(prev, out)?;
+                Path::<K, V, H>::json_to_wire(next, out)
+            }
+            ("Present", json) => {
+                let [ix, path_siblings, prev, next] =
+                    fields(json, ["ix", "path_siblings", "prev", "next"])?;
+                out.push(1);
+                write_index(out, index(ix)?);
+                list_to_wire::<Digest<H>>(path_siblings, out)?;
+                Path::<K, V, H>::json_to_wire(prev, out)?;
+                Path::<K, V, H>::json_to_wire(next, out)
+            }
+            _ => Err(JsonError::Shape("unknown variant")),
+        }
+    }
+}
+
+// an optional (key, value) pair is `null` or `{"key": hex, "value": hex}`
+fn entry_to_json<K: Encode, V: Encode>(entry: &Option<(K, V)>) -> Value {
+    match entry {
+        None => Value::Null,
+        Some((k, v)) => json!({
+            "key": hex(&k.encode()),
+            "value": hex(&v.encode()),
+        }),
+    }
+}
+
+fn entry_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+    if json.is_null() {
+        out.push(0);
+        return Ok(());
+    }
+
+    let [key, value] = fields(json, ["key", "value"])?;
+    out.push(1);
+    write_byte_string(out, &unhex(key)?);
+    write_byte_string(out, &unhex(value)?);
+    Ok(())
+}
+
+impl<K, V, H> JsonFormat for SortedKVRangeProof<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn to_json(&self) -> Value {
+        json!({
+            "start": self.start,
+            "prev": entry_to_json(&self.prev),
+            "next": entry_to_json(&self.next),
+            "siblings": list_to_json(&self.siblings),
+        })
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let [start, prev, next, siblings] =
+            fields(json, ["start", "prev", "next", "siblings"])?;
+        write_index(out, index(start)?);
+        entry_to_wire(prev, out)?;
+        entry_to_wire(next, out)?;
+        list_to_wire::<(Option<Digest<H>>, Option<Digest<H>>)>(siblings, out)
+    }
+}
+
+impl<H: Hasher> JsonFormat for CompressedPath<H> {
+    fn to_json(&self) -> Value {
+        json!({
+            "depth": self.depth(),
+            "bitmap": hex(self.bitmap()),
+            "siblings": list_to_json(self.stored_siblings()),
+        })
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let [depth, bitmap, siblings] =
+            fields(json, ["depth", "bitmap", "siblings"])?;
+        let depth = number(depth)?;
+        let bitmap = unhex(bitmap)?;
+        if bitmap.len() != 32 {
+            return Err(JsonError::Shape("bitmap must be 32 bytes"));
+        }
+
+        // a depth which doesn't fit is rejected like the codec does
+        let depth = u16::try_from(depth).map_err(|_| {
+            JsonError::Decode(DecodeError::LengthOutOfRange(depth))
+        })?;
+        out.extend_from_slice(&depth.to_le_bytes());
+        out.extend_from_slice(&bitmap);
+        list_to_wire::<Digest<H>>(siblings, out)
+    }
+}
+
+impl<H: Hasher> JsonFormat for SparseMerkleTreeProof<H> {
+    fn to_json(&self) -> Value {
+        match self {
+            SparseMerkleTreeProof::NotPresent { siblings, leaf } => json!({
+                "NotPresent": {
+                    "siblings": siblings.to_json(),
+                    "leaf": leaf.to_json(),
+                }
+            }),
+            SparseMerkleTreeProof::Present { siblings } => json!({
+                "Present": { "siblings": siblings.to_json() }
+            }),
+        }
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        match variant(json)? {
+            ("NotPresent", json) => {
+                let [siblings, leaf] = fields(json, ["siblings", "leaf"])?;
+                out.push(0);
+                CompressedPath::<H>::json_to_wire(siblings, out)?;
+                Option::<(Digest<H>, Digest<H>)>::json_to_wire(leaf, out)
+            }
+            ("Present", json) => {
+                let [siblings] = fields(json, ["siblings"])?;
+                out.push(1);
+                CompressedPath::<H>::json_to_wire(siblings, out)
+            }
+            _ => Err(JsonError::Shape("unknown variant")),
+        }
+    }
+}
+
+impl<H: Hasher> JsonFormat for MultiProofNode<H> {
+    fn to_json(&self) -> Value {
+        match self {
+            MultiProofNode::Hash(hash) => json!({ "Hash": hash.to_json() }),
+            MultiProofNode::Empty => json!("Empty"),
+            MultiProofNode::Leaf(h_k, h_v) => {
+                json!({ "Leaf": [h_k.to_json(), h_v.to_json()] })
+            }
+            MultiProofNode::Branch => json!("Branch"),
+        }
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        match json.as_str() {
+            Some("Empty") => {
+                out.push(1);
+                return Ok(());
+            }
+            Some("Branch") => {
+                out.push(3);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        match variant(json)? {
+            ("Hash", hash) => {
+                out.push(0);
+                Digest::<H>::json_to_wire(hash, out)
+            }
+            ("Leaf", leaf) => {
+                out.push(2);
+                <(Digest<H>, Digest<H>)>::json_to_wire(leaf, out)
+            }
+            _ => Err(JsonError::Shape("unknown variant")),
+        }
+    }
+}
+
+impl<H: Hasher> JsonFormat for SparseMerkleTreeMultiProof<H> {
+    fn to_json(&self) -> Value {
+        list_to_json(self.nodes())
+    }
+
+    fn json_to_wire(json: &Value, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        list_to_wire::<MultiProofNode<H>>(json, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::trees;
+    use crate::kv_trait::AuthenticatedKV;
+    use crate::sorted_kv::tests::InsertGetRemoveOp;
+    use crate::sorted_kv::SortedKV;
+    use crate::sparse_merkle_tree::SparseMerkleTree;
+    use std::fmt::Debug;
+
+    fn roundtrip<T: JsonFormat + PartialEq + Debug>(value: &T) -> T {
+        let json = to_json(value);
+        let decoded = from_json::<T>(&json).unwrap();
+        assert_eq!(&decoded, value);
+        decoded
+    }
+
+    #[quickcheck]
+    fn json_roundtrip_proofs(ops: Vec<InsertGetRemoveOp>) {
+        let (sorted_kv, smt, lookups) = trees(ops);
+
+        let comm = roundtrip(&sorted_kv.commit());
+        for k in lookups.iter() {
+            let (val, proof) = sorted_kv.get(k.clone());
+            let proof = roundtrip(&proof);
+            <SortedKV>::check_proof(k.clone(), val, &proof, &comm).unwrap();
+        }
+        for pair in lookups.windows(2) {
+            let (entries, proof) = sorted_kv.get_range(&pair[0], &pair[1]);
+            let proof = roundtrip(&proof);
+            <SortedKV>::check_range_proof(
+                &pair[0], &pair[1], &entries, &proof, &comm,
+            )
+            .unwrap();
+        }
+
+        let comm = roundtrip(&smt.commit());
+        for k in lookups.iter() {
+            let (val, proof) = smt.get(k.clone());
+            let proof = roundtrip(&proof);
+            proof.check(k, val.as_ref(), &comm).unwrap();
+        }
+
+        let (results, proof) = smt.get_many(&lookups);
+        roundtrip(&proof).check(&lookups, &results, &comm).unwrap();
+    }
+
+    #[test]
+    fn json_every_variant() {
+        let sorted_kv = (0..4).fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(i.to_string(), i.to_string())
+        });
+        let smt = (0..4).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+
+        let (_, present) = sorted_kv.get("1".to_string());
+        let (_, not_present) = sorted_kv.get("10".to_string());
+        for (proof, tag) in [(present, "Present"), (not_present, "NotPresent")]
+        {
+            let json: Value = serde_json::from_str(&to_json(&proof)).unwrap();
+            assert!(json.get(tag).is_some());
+            roundtrip(&proof);
+        }
+
+        // a present key, and absent keys whose paths end in an empty
+        // subtree and in another leaf
+        let mut proofs = vec![smt.get("1".to_string()).1];
+        for leaf in [false, true].iter() {
+            let proof = (4..)
+                .map(|i| smt.get(i.to_string()).1)
+                .find(|proof| match proof {
+                    SparseMerkleTreeProof::NotPresent { leaf: l, .. } => {
+                        l.is_some() == *leaf
+                    }
+                    _ => false,
+                })
+                .unwrap();
+            proofs.push(proof);
+        }
+        for proof in proofs.iter() {
+            roundtrip(proof);
+        }
+
+        let json = to_json(&<SparseMerkleTree>::new().get("0".to_string()).1);
+        assert_eq!(
+            json,
+            format!(
+                "{{\"NotPresent\":{{\"leaf\":null,\"siblings\":{{\"bitmap\":\"{}\",\"depth\":0,\"siblings\":[]}}}}}}",
+                "00".repeat(32)
+            )
+        );
+    }
+
+    #[test]
+    fn json_rejects_malformed_input() {
+        let smt = (0..10).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+        let (_, proof) = smt.get("3".to_string());
+        let json: Value = serde_json::from_str(&to_json(&proof)).unwrap();
+        let decode = |json: &Value| {
+            from_json::<SparseMerkleTreeProof>(&json.to_string())
+        };
+
+        assert!(matches!(
+            from_json::<SparseMerkleTreeProof>("{"),
+            Err(JsonError::Syntax(_))
+        ));
+        assert_eq!(
+            decode(&json!({ "Maybe": json["Present"] })),
+            Err(JsonError::Shape("unknown variant"))
+        );
+
+        let mut extra = json.clone();
+        extra["Present"]["leaf"] = Value::Null;
+        assert_eq!(decode(&extra), Err(JsonError::Shape("unexpected field")));
+
+        let mut bad_hex = json.clone();
+        bad_hex["Present"]["siblings"]["siblings"][0] = json!("zz".repeat(32));
+        assert_eq!(
+            decode(&bad_hex),
+            Err(JsonError::Shape("invalid hex digit"))
+        );
+
+        // the same validation as the binary codec
+        let mut deep = json.clone();
+        deep["Present"]["siblings"]["depth"] = json!(257);
+        assert_eq!(
+            decode(&deep),
+            Err(JsonError::Decode(DecodeError::LengthOutOfRange(257)))
+        );
+
+        let mut bitmap = json.clone();
+        bitmap["Present"]["siblings"]["bitmap"] = json!("00".repeat(32));
+        assert!(matches!(
+            decode(&bitmap),
+            Err(JsonError::Decode(DecodeError::Invalid(_)))
+        ));
+
+        let path: Value = serde_json::from_str(&to_json(&MerkleLookupPath::<
+            Vec<u8>,
+            Vec<u8>,
+        > {
+            key: vec![0xff],
+            value: vec![],
+            siblings: vec![],
+        }))
+        .unwrap();
+        assert_eq!(
+            from_json::<MerkleLookupPath>(&path.to_string()),
+            Err(JsonError::Decode(DecodeError::Invalid(
+                "not a valid key or value"
+            )))
+        );
+
+        // an index is rejected where it doesn't fit in a usize, rather
+        // than truncated
+        let lookup = json!({ "NotPresent": {
+            "next_ix": u64::MAX,
+            "prev": null,
+            "next": null,
+        } });
+        let decoded = from_json::<SortedKVLookup>(&lookup.to_string());
+        if usize::try_from(u64::MAX).is_err() {
+            assert_eq!(
+                decoded,
+                Err(JsonError::Decode(DecodeError::LengthOutOfRange(u64::MAX)))
+            );
+        } else {
+            assert!(decoded.is_ok());
+        }
+    }
+}
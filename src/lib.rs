@@ -1,7 +1,14 @@
+pub mod codec;
 pub mod common;
+pub mod journal;
+#[cfg(feature = "json")]
+pub mod json;
 pub mod kv_trait;
+pub mod node_store;
 pub mod sorted_kv;
 pub mod sparse_merkle_tree;
+pub mod stored_smt;
+pub mod versioned_smt;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck_macros;
@@ -0,0 +1,721 @@
+use crate::common::{Decode, Digest, Encode, Hasher, Sha256};
+use crate::sparse_merkle_tree::smt_util::{self, EmptyHashes};
+use crate::sparse_merkle_tree::Node;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A `SparseMerkleTree` node as it is read from and written to a
+/// `NodeStore`, with its children given by the store's references `R`.
+///
+/// Empty subtrees are never written, a store hands out a reference to the
+/// empty subtree at each depth instead, see `NodeStore::empty`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredNode<K, V, H: Hasher = Sha256, R = Digest<H>> {
+    Empty,
+    Leaf {
+        key: Digest<H>,
+        original_key: K,
+        value: V,
+    },
+    /// A branch, with the number of leaves below it.
+    Branch {
+        left: R,
+        right: R,
+        len: usize,
+    },
+}
+
+/// Where the nodes of a `SparseMerkleTree` are kept.
+///
+/// A `MemoryNodeStore` holds them as reference-counted nodes pointing at
+/// each other, which is what a tree uses by default. A `FileNodeStore`
+/// writes them to a file, and refers to them by digest. Either way nodes
+/// are immutable once written, so every root which was ever committed
+/// stays readable for as long as its nodes are kept.
+pub trait NodeStore<K: Clone, V: Clone, H: Hasher> {
+    /// How a branch refers to its children, and a tree to its root.
+    type Ref: Clone;
+
+    /// The empty subtree at `depth`.
+    fn empty(&self, depth: usize) -> Self::Ref;
+
+    /// The digest of the subtree `node` at `depth`.
+    fn hash(&self, node: &Self::Ref, depth: usize) -> Digest<H>;
+
+    fn is_empty(&self, node: &Self::Ref, depth: usize) -> bool {
+        self.hash(node, depth) == self.hash(&self.empty(depth), depth)
+    }
+
+    /// Read the subtree `node` at `depth`.
+    #[allow(clippy::type_complexity)]
+    fn get<'a>(
+        &'a self,
+        node: &'a Self::Ref,
+        depth: usize,
+    ) -> io::Result<StoredNode<Cow<'a, K>, Cow<'a, V>, H, Self::Ref>>;
+
+    /// Write `node` as the subtree at `depth`, returning a reference to
+    /// it. Writing a node which is already stored does nothing.
+    fn put(
+        &mut self,
+        node: StoredNode<K, V, H, Self::Ref>,
+        depth: usize,
+    ) -> io::Result<Self::Ref>;
+
+    /// Make every node written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// The `NodeStore` of an in-memory `SparseMerkleTree`, where a branch
+/// holds its children.
+///
+/// Children are reference-counted and never modified, so trees which
+/// share history share every node off the paths where they differ.
+#[derive(Debug, Clone)]
+pub struct MemoryNodeStore<K = String, V = String, H: Hasher = Sha256> {
+    empty: EmptyHashes<H>,
+    // every empty subtree, whatever its depth
+    empty_node: Arc<Node<K, V, H>>,
+}
+
+impl<K, V, H: Hasher> MemoryNodeStore<K, V, H> {
+    pub fn new() -> Self {
+        MemoryNodeStore {
+            empty: EmptyHashes::get(),
+            empty_node: Arc::new(Node::Empty),
+        }
+    }
+}
+
+impl<K, V, H: Hasher> Default for MemoryNodeStore<K, V, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, H> NodeStore<K, V, H> for MemoryNodeStore<K, V, H>
+where
+    K: Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    type Ref = Arc<Node<K, V, H>>;
+
+    fn empty(&self, _depth: usize) -> Self::Ref {
+        self.empty_node.clone()
+    }
+
+    fn hash(&self, node: &Self::Ref, depth: usize) -> Digest<H> {
+        node.hash_with(depth, self.empty)
+    }
+
+    fn is_empty(&self, node: &Self::Ref, _depth: usize) -> bool {
+        matches!(node.as_ref(), Node::Empty)
+    }
+
+    fn get<'a>(
+        &'a self,
+        node: &'a Self::Ref,
+        _depth: usize,
+    ) -> io::Result<StoredNode<Cow<'a, K>, Cow<'a, V>, H, Self::Ref>> {
+        Ok(match node.as_ref() {
+            Node::Empty => StoredNode::Empty,
+            Node::Leaf {
+                key,
+                original_key,
+                value,
+                ..
+            } => StoredNode::Leaf {
+                key: *key,
+                original_key: Cow::Borrowed(original_key),
+                value: Cow::Borrowed(value),
+            },
+            Node::Branch {
+                left, right, len, ..
+            } => StoredNode::Branch {
+                left: left.clone(),
+                right: right.clone(),
+                len: *len,
+            },
+        })
+    }
+
+    fn put(
+        &mut self,
+        node: StoredNode<K, V, H, Self::Ref>,
+        depth: usize,
+    ) -> io::Result<Self::Ref> {
+        Ok(match node {
+            StoredNode::Empty => self.empty(depth),
+            StoredNode::Leaf {
+                key,
+                original_key,
+                value,
+            } => Arc::new(Node::Leaf {
+                key,
+                hash: smt_util::hash_leaf(key, smt_util::hash_value(&value)),
+                original_key,
+                value,
+            }),
+            StoredNode::Branch { left, right, len } => Arc::new(Node::Branch {
+                hash: smt_util::hash_branch(
+                    self.hash(&left, depth + 1),
+                    self.hash(&right, depth + 1),
+                ),
+                left,
+                right,
+                len,
+            }),
+        })
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const FILE_MAGIC: &[u8; 8] = b"SMTNODE2";
+const LEAF_TAG: u8 = 0;
+const BRANCH_TAG: u8 = 1;
+const DIGEST_LEN: usize = 32;
+
+/**
+ * A `NodeStore` which appends nodes to a single file, and refers to them
+ * by digest.
+ *
+ * The file is an 8-byte magic header followed by one record per node:
+ *
+ * ```text
+ *  digest (32) | LEAF_TAG   (1) | key (32)  | key len (u64 LE) | key
+ *                               | value len (u64 LE) | value
+ *  digest (32) | BRANCH_TAG (1) | left (32) | right (32) | len (u64 LE)
+ * ```
+ *
+ * where a leaf holds its hashed key, then its original key and its value
+ * as they are encoded, and a branch holds the number of leaves below it.
+ *
+ * Only the offset of each record is kept in memory; nodes are read back
+ * from the file and checked against their digest on every `get`. The
+ * number of leaves below a branch isn't part of its digest, so it can't be
+ * checked. A record cut off by a crash is dropped when the file is
+ * reopened.
+ */
+pub struct FileNodeStore<K = String, V = String, H: Hasher = Sha256> {
+    file: File,
+    index: HashMap<Digest<H>, u64>,
+    len: u64,
+    empty: EmptyHashes<H>,
+    entries: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, H: Hasher> fmt::Debug for FileNodeStore<K, V, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileNodeStore")
+            .field("file", &self.file)
+            .field("nodes", &self.index.len())
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn missing_node() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "node missing from store")
+}
+
+fn read_digest<H>(r: &mut impl Read) -> io::Result<Digest<H>> {
+    let mut buf = [0u8; DIGEST_LEN];
+    r.read_exact(&mut buf)?;
+    Ok(Digest::from(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl<K, V, H: Hasher> FileNodeStore<K, V, H> {
+    /// Open the store at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let file_len = file.metadata()?.len();
+        let mut index = HashMap::new();
+        let mut len = FILE_MAGIC.len() as u64;
+        if file_len == 0 {
+            file.write_all(FILE_MAGIC)?;
+        } else {
+            let mut reader = BufReader::new(&file);
+            let mut magic = [0u8; 8];
+            reader
+                .read_exact(&mut magic)
+                .map_err(|_| invalid_data("not a node store"))?;
+            if &magic != FILE_MAGIC {
+                return Err(invalid_data("not a node store"));
+            }
+
+            // scan the records, stopping at the first one which is cut off
+            loop {
+                match Self::scan_record(&mut reader, file_len - len) {
+                    Ok(Some((hash, record_len))) => {
+                        index.insert(hash, len);
+                        len += record_len;
+                    }
+                    Ok(None) => break,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            drop(reader);
+
+            if len < file_len {
+                file.set_len(len)?;
+            }
+        }
+
+        Ok(FileNodeStore {
+            file,
+            index,
+            len,
+            empty: EmptyHashes::get(),
+            entries: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // scan_record skips over the next record, returning its digest and length.
+    // `remaining` is the number of bytes left in the file. A record running past
+    // the end of the file, or with a length too large to add up, is cut off.
+    fn scan_record(
+        reader: &mut BufReader<&File>,
+        remaining: u64,
+    ) -> io::Result<Option<(Digest<H>, u64)>> {
+        if remaining == 0 {
+            return Ok(None);
+        }
+
+        let hash = read_digest(reader)?;
+        let mut tag = [0u8; 1];
+        reader.read_exact(&mut tag)?;
+
+        let mut record_len = (DIGEST_LEN + 1) as u64;
+        match tag[0] {
+            LEAF_TAG => {
+                reader.seek_relative(DIGEST_LEN as i64)?;
+                record_len += DIGEST_LEN as u64;
+
+                // the original key, then the value
+                for _ in 0..2 {
+                    let len = read_u64(reader)?;
+                    record_len = match record_len
+                        .checked_add(8)
+                        .and_then(|n| n.checked_add(len))
+                    {
+                        Some(n) if n <= remaining => n,
+                        _ => return Ok(None),
+                    };
+                    reader.seek_relative(len as i64)?;
+                }
+            }
+            BRANCH_TAG => {
+                let body_len = 2 * DIGEST_LEN + 8;
+                record_len += body_len as u64;
+                if record_len > remaining {
+                    return Ok(None);
+                }
+                reader.seek_relative(body_len as i64)?;
+            }
+            _ => return Err(invalid_data("unknown node tag")),
+        }
+
+        Ok(Some((hash, record_len)))
+    }
+
+    // hash_of is the digest `node` is addressed by, the same as the digest
+    // of the matching in-memory node.
+    fn hash_of(
+        &self,
+        node: &StoredNode<K, V, H, Digest<H>>,
+        depth: usize,
+    ) -> Digest<H>
+    where
+        V: Encode,
+    {
+        match node {
+            StoredNode::Empty => self.empty.at(depth),
+            StoredNode::Leaf { key, value, .. } => {
+                smt_util::hash_leaf(*key, smt_util::hash_value(value))
+            }
+            StoredNode::Branch { left, right, .. } => {
+                smt_util::hash_branch(*left, *right)
+            }
+        }
+    }
+}
+
+impl<K, V, H> FileNodeStore<K, V, H>
+where
+    K: Encode + Decode,
+    V: Encode + Decode,
+    H: Hasher,
+{
+    fn read_node(
+        &self,
+        offset: u64,
+    ) -> io::Result<StoredNode<K, V, H, Digest<H>>> {
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(offset))?;
+
+        // the length-prefixed bytes of an original key or a value
+        let read_bytes = |reader: &mut BufReader<&File>| {
+            let len = read_u64(reader)?;
+            if len > self.len - offset {
+                return Err(invalid_data("length out of range"));
+            }
+            let mut bytes = vec![0u8; len as usize];
+            reader.read_exact(&mut bytes)?;
+            Ok(bytes)
+        };
+
+        let hash = read_digest(&mut reader)?;
+        let mut tag = [0u8; 1];
+        reader.read_exact(&mut tag)?;
+
+        let node = match tag[0] {
+            LEAF_TAG => {
+                let key = read_digest(&mut reader)?;
+                let original_key = K::decode(&read_bytes(&mut reader)?)
+                    .ok_or_else(|| invalid_data("not a valid key"))?;
+                let value = V::decode(&read_bytes(&mut reader)?)
+                    .ok_or_else(|| invalid_data("not a valid value"))?;
+                if smt_util::hash_key::<K, H>(&original_key) != key {
+                    return Err(invalid_data("key doesn't match its hash"));
+                }
+                StoredNode::Leaf {
+                    key,
+                    original_key,
+                    value,
+                }
+            }
+            BRANCH_TAG => StoredNode::Branch {
+                left: read_digest(&mut reader)?,
+                right: read_digest(&mut reader)?,
+                len: usize::try_from(read_u64(&mut reader)?)
+                    .map_err(|_| invalid_data("length out of range"))?,
+            },
+            _ => return Err(invalid_data("unknown node tag")),
+        };
+
+        if self.hash_of(&node, 0) != hash {
+            return Err(invalid_data("node doesn't match its digest"));
+        }
+
+        Ok(node)
+    }
+}
+
+impl<K, V, H> NodeStore<K, V, H> for FileNodeStore<K, V, H>
+where
+    K: Encode + Decode + Clone,
+    V: Encode + Decode + Clone,
+    H: Hasher,
+{
+    type Ref = Digest<H>;
+
+    fn empty(&self, depth: usize) -> Self::Ref {
+        self.empty.at(depth)
+    }
+
+    fn hash(&self, node: &Self::Ref, _depth: usize) -> Digest<H> {
+        *node
+    }
+
+    fn get<'a>(
+        &'a self,
+        node: &'a Self::Ref,
+        depth: usize,
+    ) -> io::Result<StoredNode<Cow<'a, K>, Cow<'a, V>, H, Self::Ref>> {
+        if *node == self.empty.at(depth) {
+            return Ok(StoredNode::Empty);
+        }
+
+        let offset = self.index.get(node).ok_or_else(missing_node)?;
+        Ok(match self.read_node(*offset)? {
+            StoredNode::Empty => StoredNode::Empty,
+            StoredNode::Leaf {
+                key,
+                original_key,
+                value,
+            } => StoredNode::Leaf {
+                key,
+                original_key: Cow::Owned(original_key),
+                value: Cow::Owned(value),
+            },
+            StoredNode::Branch { left, right, len } => {
+                StoredNode::Branch { left, right, len }
+            }
+        })
+    }
+
+    fn put(
+        &mut self,
+        node: StoredNode<K, V, H, Self::Ref>,
+        depth: usize,
+    ) -> io::Result<Self::Ref> {
+        let hash = self.hash_of(&node, depth);
+        if self.index.contains_key(&hash) {
+            return Ok(hash);
+        }
+
+        fn push_bytes(record: &mut Vec<u8>, bytes: &[u8]) {
+            record.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            record.extend_from_slice(bytes);
+        }
+
+        let mut record = hash.as_ref().to_vec();
+        match &node {
+            StoredNode::Empty => return Ok(hash),
+            StoredNode::Leaf {
+                key,
+                original_key,
+                value,
+            } => {
+                record.push(LEAF_TAG);
+                record.extend_from_slice(key.as_ref());
+                push_bytes(&mut record, &original_key.encode());
+                push_bytes(&mut record, &value.encode());
+            }
+            StoredNode::Branch { left, right, len } => {
+                record.push(BRANCH_TAG);
+                record.extend_from_slice(left.as_ref());
+                record.extend_from_slice(right.as_ref());
+                record.extend_from_slice(&(*len as u64).to_le_bytes());
+            }
+        }
+
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&record)?;
+        self.index.insert(hash, self.len);
+        self.len += record.len() as u64;
+
+        Ok(hash)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A path in the temp directory which is removed on drop.
+    pub struct TempPath(pub PathBuf);
+
+    impl TempPath {
+        pub fn new(name: &str) -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+            TempPath(std::env::temp_dir().join(format!(
+                "ads-{}-{}-{}",
+                name,
+                std::process::id(),
+                n
+            )))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    type Store = FileNodeStore<String, String, Sha256>;
+
+    // nodes writes a leaf and a branch over it and an empty subtree to
+    // `store`, returning their digests.
+    fn nodes(store: &mut Store) -> Vec<Digest> {
+        let leaf = store
+            .put(
+                StoredNode::Leaf {
+                    key: smt_util::hash_key("0"),
+                    original_key: "0".to_string(),
+                    value: "value".to_string(),
+                },
+                1,
+            )
+            .unwrap();
+        let branch = store
+            .put(
+                StoredNode::Branch {
+                    left: leaf,
+                    right: store.empty(1),
+                    len: 1,
+                },
+                0,
+            )
+            .unwrap();
+        vec![leaf, branch]
+    }
+
+    fn get(
+        store: &Store,
+        hash: &Digest,
+    ) -> io::Result<StoredNode<String, String>> {
+        Ok(match store.get(hash, 0)? {
+            StoredNode::Empty => StoredNode::Empty,
+            StoredNode::Leaf {
+                key,
+                original_key,
+                value,
+            } => StoredNode::Leaf {
+                key,
+                original_key: original_key.into_owned(),
+                value: value.into_owned(),
+            },
+            StoredNode::Branch { left, right, len } => {
+                StoredNode::Branch { left, right, len }
+            }
+        })
+    }
+
+    #[test]
+    fn node_store_roundtrip() {
+        let path = TempPath::new("node-store");
+        let mut file = Store::open(&path.0).unwrap();
+
+        let hashes = nodes(&mut file);
+        assert_eq!(nodes(&mut file), hashes);
+        assert_eq!(file.len(), 2);
+
+        let expected = [
+            StoredNode::Leaf {
+                key: smt_util::hash_key("0"),
+                original_key: "0".to_string(),
+                value: "value".to_string(),
+            },
+            StoredNode::Branch {
+                left: hashes[0],
+                right: file.empty(1),
+                len: 1,
+            },
+        ];
+        for (hash, node) in hashes.iter().zip(expected.iter()) {
+            assert_eq!(&get(&file, hash).unwrap(), node);
+        }
+        assert_eq!(get(&file, &file.empty(0)).unwrap(), StoredNode::Empty);
+        let missing = smt_util::hash_key("1");
+        assert_eq!(
+            get(&file, &missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        file.sync().unwrap();
+        drop(file);
+
+        let file = Store::open(&path.0).unwrap();
+        for (hash, node) in hashes.iter().zip(expected.iter()) {
+            assert_eq!(&get(&file, hash).unwrap(), node);
+        }
+    }
+
+    #[test]
+    fn node_store_drops_cut_off_record() {
+        let path = TempPath::new("node-store-cut");
+        let mut file = Store::open(&path.0).unwrap();
+        let hashes = nodes(&mut file);
+        drop(file);
+
+        let full_len = std::fs::metadata(&path.0).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path.0).unwrap();
+        file.set_len(full_len - 1).unwrap();
+        drop(file);
+
+        let mut file = Store::open(&path.0).unwrap();
+        assert!(get(&file, &hashes[0]).is_ok());
+        assert!(get(&file, &hashes[1]).is_err());
+
+        // the cut off record can be written again
+        assert_eq!(nodes(&mut file), hashes);
+        drop(file);
+        let file = Store::open(&path.0).unwrap();
+        assert!(get(&file, &hashes[1]).is_ok());
+    }
+
+    #[test]
+    fn node_store_drops_record_with_overflowing_length() {
+        let path = TempPath::new("node-store-overflow");
+        let mut file = Store::open(&path.0).unwrap();
+        let hashes = nodes(&mut file);
+        let leaf = file
+            .put(
+                StoredNode::Leaf {
+                    key: smt_util::hash_key("1"),
+                    original_key: "1".to_string(),
+                    value: "value".to_string(),
+                },
+                1,
+            )
+            .unwrap();
+        drop(file);
+
+        // the second leaf is last, and its value "value" follows its length
+        let full_len = std::fs::metadata(&path.0).unwrap().len();
+        let mut file = OpenOptions::new().write(true).open(&path.0).unwrap();
+        file.seek(SeekFrom::Start(full_len - 5 - 8)).unwrap();
+        file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        drop(file);
+
+        let file = Store::open(&path.0).unwrap();
+        assert!(get(&file, &hashes[1]).is_ok());
+        assert!(get(&file, &leaf).is_err());
+        assert_eq!(file.len(), 2);
+    }
+
+    #[test]
+    fn node_store_checks_keys_against_their_hash() {
+        let path = TempPath::new("node-store-key");
+        let mut file = Store::open(&path.0).unwrap();
+        let leaf = file
+            .put(
+                StoredNode::Leaf {
+                    key: smt_util::hash_key("0"),
+                    original_key: "1".to_string(),
+                    value: "value".to_string(),
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(
+            get(&file, &leaf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
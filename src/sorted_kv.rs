@@ -1,33 +1,53 @@
 #![deny(warnings)]
 use crate::{common, kv_trait};
-use common::{zero_digest, Digest};
+use common::{zero_digest, Digest, Encode, Hasher, Sha256};
 use kv_trait::AuthenticatedKV;
 use std::cmp::Ordering;
+use std::iter::FromIterator;
 
-pub fn empty_kv_hash() -> Digest {
+pub use sortedkv_util::MerkleLookupPath;
+
+pub fn empty_kv_hash<H>() -> Digest<H> {
     zero_digest()
 }
 
 /**
- * A `SortedKV` is a list of entries sorted by key.
+ * A `SortedKV` is a list of entries sorted by key, with at most one entry
+ * per key.
  *
  * The `commitment` is calculated recursively as a binary tree, to allow
  * for shorter proofs.
  *
- * NOTE: There are multiple representations of any given associative array
- * as a `SortedKV`. The binary search in `get()` "biases to the right", and
- * specifically selects the _last_ `(K,V)` pair in the underlying array
- * `store`. For example, if `store` is `vec![(0,0),(1,1),(1,5),(2,2)]`, the
- * result of `get(1)` will be `Some(5)`
+ * `insert()` replaces the entry of a key that is already present, and
+ * `remove()` deletes it, so the keys in `store` are strictly increasing.
+ * Each associative array then has exactly one representation as a
+ * `SortedKV`, and its commitment only depends on the entries, not on the
+ * order of the writes that produced them.
+ *
+ * Keys and values can be any type with a canonical byte encoding, which is
+ * what gets hashed; keys are ordered by their `Ord` implementation.
+ *
+ * Every hash of the tree is kept, so a proof reads its O(log n) siblings
+ * instead of rehashing them, and a write only rehashes the subtrees
+ * covering the entries at or after the index it changes. Appending or
+ * removing the last entry costs O(log n) hashes, but an insert or remove in
+ * the middle shifts the entries after it to other leaves, and those are
+ * rehashed as well, so a write at the front costs O(n) hashes. Leaves are
+ * positions in the list, so O(log n) writes everywhere would need a
+ * different commitment, e.g. one whose tree shape depends on the keys
+ * instead of their indexes. To build a whole list, `from_sorted_iter()`,
+ * `collect()` and `extend()` hash every entry once instead.
  */
 #[derive(Debug, Clone)]
-pub struct SortedKV {
-    commitment: Digest,
-    store: Vec<(String, String)>,
+pub struct SortedKV<K = String, V = String, H: Hasher = Sha256> {
+    store: Vec<(K, V)>,
+    // levels[0] holds the hash of each entry, and each next level the
+    // hashes of the pairs of the level below, up to the root
+    levels: Vec<Vec<Digest<H>>>,
 }
 
-#[derive(Debug, Clone)]
-pub enum SortedKVLookup {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SortedKVLookup<K = String, V = String, H: Hasher = Sha256> {
     /// A pair of entries `(K1,V1),(K2,V2)` which appear next to each
     /// other in the list with `K1 < K` and K2 > K` prove that `K` does
     /// not have an entry.
@@ -38,49 +58,104 @@ pub enum SortedKVLookup {
         /// K, and the sibling hashes to get up to the root commitment.
         ///
         /// `None` if and only if `next_ix == 0`
-        prev: Option<sortedkv_util::MerkleLookupPath>,
+        prev: Option<sortedkv_util::MerkleLookupPath<K, V, H>>,
         /// The next (index `next_ix`) (K,V) pair with K_next > K, and
         /// the sibling hashes to get up to the root commitment.
         ///
         /// `None` if and only if `next_ix == store.len()`
-        next: Option<sortedkv_util::MerkleLookupPath>,
+        next: Option<sortedkv_util::MerkleLookupPath<K, V, H>>,
     },
     /// The location of (K,V) and a pair of entries `(K1,V1),(K2,V2)`
     /// which appear on either side of `(K,V)` in the list next
-    /// `K1 <= K` and K2 > K` prove that looking up `K` will result in
+    /// `K1 < K` and K2 > K` prove that looking up `K` will result in
     /// `Some(V)`.
     Present {
         /// The index which contains this value
         ix: usize,
         /// The path of sibling hashes to the root
-        path_siblings: Vec<Digest>,
-        /// The previous (index `ix-1`) (K,V) pair with K_prev <= K, and
+        path_siblings: Vec<Digest<H>>,
+        /// The previous (index `ix-1`) (K,V) pair with K_prev < K, and
         /// the sibling hashes to get up to the root commitment.
         ///
         /// `None` if and only if `ix == 0`
-        prev: Option<sortedkv_util::MerkleLookupPath>,
+        prev: Option<sortedkv_util::MerkleLookupPath<K, V, H>>,
         /// The next (index `ix+1`) (K,V) pair with K_next > K, and the
         /// sibling hashes to get up to the root commitment.
         ///
         /// `None` if and only if `ix + 1 == store.len()`
-        next: Option<sortedkv_util::MerkleLookupPath>,
+        next: Option<sortedkv_util::MerkleLookupPath<K, V, H>>,
     },
 }
 
-impl SortedKV {
+/// A proof that a run of consecutive entries of a `SortedKV` is every
+/// entry with a key in a range `a <= K < b`, see `SortedKV::get_range`.
+///
+/// The entries on either side of the run are opened to show that they are
+/// outside the range, and the run together with them is authenticated as
+/// one contiguous slice of the list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortedKVRangeProof<K = String, V = String, H: Hasher = Sha256> {
+    /// The index of the first entry of the run
+    pub start: usize,
+    /// The entry before the run, with K_prev < a.
+    ///
+    /// `None` if and only if `start == 0`
+    pub prev: Option<(K, V)>,
+    /// The entry after the run, with K_next >= b.
+    ///
+    /// `None` if and only if the run ends the list
+    pub next: Option<(K, V)>,
+    /// For each level of the tree below the root, from the leaves up, the
+    /// sibling hashes to the left and to the right of the nodes covering
+    /// `prev`, the run and `next`. A side is `None` where the outermost
+    /// node on it is paired with another covering node.
+    pub siblings: Vec<RangeSiblings<H>>,
+}
+
+/// The (left, right) siblings of one level of a `SortedKVRangeProof`.
+pub type RangeSiblings<H = Sha256> = (Option<Digest<H>>, Option<Digest<H>>);
+
+/// Why `SortedKV::from_sorted_iter` rejected its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FromSortedIterError {
+    /// The entry at `index` has a smaller key than the entry before it.
+    OutOfOrder { index: usize },
+    /// The entry at `index` has the same key as the entry before it.
+    DuplicateKey { index: usize },
+}
+
+impl core::fmt::Display for FromSortedIterError {
+    fn fmt(
+        &self,
+        fmt: &mut std::fmt::Formatter<'_>,
+    ) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            FromSortedIterError::OutOfOrder { index } => {
+                write!(fmt, "entry {} is out of order", index)
+            }
+            FromSortedIterError::DuplicateKey { index } => {
+                write!(fmt, "entry {} repeats the key before it", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FromSortedIterError {}
+
+impl<K: Ord, V, H: Hasher> SortedKV<K, V, H> {
     // a right-biased binary search, which returns the index of the
-    // rightmost `(k,v)` pair with `k <= key`. If `key` isn't present in
-    // the array, this returns the index _before_ where `key` would be
-    // inserted.
+    // rightmost `(k,v)` pair with `k <= key`, which is the entry of `key`
+    // if it is present. If `key` isn't present in the array, this returns
+    // the index _before_ where `key` would be inserted.
     //
     // NOTE: this assumes that self.store is nonempty.
-    fn binary_search(&self, key: &str) -> usize {
+    fn binary_search(&self, key: &K) -> usize {
         let (mut lo, mut hi) = (0, self.store.len());
 
         // binary search for `key`
         while lo + 1 < hi {
             let mid = lo + (hi - lo) / 2;
-            if self.store[mid].0.as_str() <= key {
+            if self.store[mid].0 <= *key {
                 lo = mid;
             } else {
                 hi = mid;
@@ -92,21 +167,191 @@ impl SortedKV {
     }
 }
 
-impl AuthenticatedKV for SortedKV {
-    type K = String;
-    type V = String;
-    type LookupProof = SortedKVLookup;
-    type Commitment = Digest;
+// push the entries of `sorted`, which are sorted by key, onto `out`, keeping
+// only the last entry of each key
+fn push_last_of_each<K: Ord, V>(
+    out: &mut Vec<(K, V)>,
+    sorted: impl IntoIterator<Item = (K, V)>,
+) {
+    for (k, v) in sorted {
+        match out.last_mut() {
+            Some(last) if last.0 == k => last.1 = v,
+            _ => out.push((k, v)),
+        }
+    }
+}
+
+impl<K, V, H> SortedKV<K, V, H>
+where
+    K: Encode + Ord + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    /// Build a `SortedKV` from entries in order of strictly increasing
+    /// keys, hashing each entry once.
+    pub fn from_sorted_iter(
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, FromSortedIterError> {
+        let mut store: Vec<(K, V)> = vec![];
+        for (index, (k, v)) in entries.into_iter().enumerate() {
+            if let Some((prev, _)) = store.last() {
+                match prev.cmp(&k) {
+                    Ordering::Less => {}
+                    Ordering::Equal => {
+                        return Err(FromSortedIterError::DuplicateKey { index })
+                    }
+                    Ordering::Greater => {
+                        return Err(FromSortedIterError::OutOfOrder { index })
+                    }
+                }
+            }
+            store.push((k, v));
+        }
+
+        let mut levels = vec![];
+        sortedkv_util::rehash_from(&mut levels, &store, 0);
+        Ok(SortedKV { store, levels })
+    }
+
+    /// Every entry with `a <= K < b`, in order, with a proof that none
+    /// were left out.
+    pub fn get_range(
+        &self,
+        a: &K,
+        b: &K,
+    ) -> (Vec<(K, V)>, SortedKVRangeProof<K, V, H>) {
+        let lo = self.store.partition_point(|(k, _)| k < a);
+        let hi = lo + self.store[lo..].partition_point(|(k, _)| k < b);
+
+        // the run and the entries on either side of it are covered
+        let first = lo.saturating_sub(1);
+        let end = (hi + 1).min(self.store.len());
+
+        let proof = SortedKVRangeProof {
+            start: lo,
+            prev: lo.checked_sub(1).map(|ix| self.store[ix].clone()),
+            next: self.store.get(hi).cloned(),
+            siblings: sortedkv_util::prove_range(first, end, &self.levels),
+        };
+        (self.store[lo..hi].to_vec(), proof)
+    }
+
+    /// Check that `entries` are every entry with `a <= K < b` in the list
+    /// committed to by `comm`, in order of strictly increasing keys.
+    //
+    // prev, the entries and next are hashed into the covered leaves, which are
+    // hashed up to the root level by level. Where prev or next is missing, the
+    // run must reach that end of the list: the run must start at index 0, or
+    // every sibling to its right must be an empty subtree.
+    pub fn check_range_proof(
+        a: &K,
+        b: &K,
+        entries: &[(K, V)],
+        pf: &SortedKVRangeProof<K, V, H>,
+        comm: &Digest<H>,
+    ) -> Option<()> {
+        let in_range = |(k, _): &(K, V)| a <= k && k < b;
+        if !entries.iter().all(in_range)
+            || entries.windows(2).any(|pair| pair[0].0 >= pair[1].0)
+        {
+            return None;
+        }
+        if pf.prev.as_ref().map_or(pf.start != 0, |(k, _)| k >= a)
+            || pf.next.as_ref().is_some_and(|(k, _)| k < b)
+        {
+            return None;
+        }
+
+        let leaves: Vec<_> = pf
+            .prev
+            .iter()
+            .chain(entries.iter())
+            .chain(pf.next.iter())
+            .map(|(k, v)| sortedkv_util::hash_kv(k, v))
+            .collect();
+        // a proof can't have an entry before index 0
+        let first = pf.start.checked_sub(pf.prev.is_some() as usize)?;
+
+        let root = sortedkv_util::root_from_range(
+            first,
+            leaves,
+            &pf.siblings,
+            pf.next.is_none(),
+        )?;
+        if root != *comm {
+            return None;
+        }
+
+        Some(())
+    }
+}
+
+/// Collects entries in any order. A key which appears more than once keeps
+/// its last value, like repeated `insert()`s.
+impl<K, V, H> FromIterator<(K, V)> for SortedKV<K, V, H>
+where
+    K: Encode + Ord + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        let mut kv = Self::new();
+        kv.extend(entries);
+        kv
+    }
+}
+
+/// Inserts entries in any order, with the same result as `insert()`ing
+/// them one by one, but only the entries from the smallest new key on are
+/// moved and rehashed, once.
+impl<K, V, H> Extend<(K, V)> for SortedKV<K, V, H>
+where
+    K: Encode + Ord + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, entries: I) {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        // a stable sort keeps repeated keys in the order they were given
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let smallest = match entries.first() {
+            Some((k, _)) => k,
+            None => return,
+        };
+
+        // the entries before the smallest new key keep their index
+        let ix = self.store.partition_point(|(k, _)| k < smallest);
+        let mut merged = self.store.split_off(ix);
+        merged.extend(entries);
+        // both halves are sorted, and the old entries of a key come first
+        merged.sort_by(|a, b| a.0.cmp(&b.0));
+        push_last_of_each(&mut self.store, merged);
+
+        sortedkv_util::rehash_from(&mut self.levels, &self.store, ix);
+    }
+}
+
+impl<K, V, H> AuthenticatedKV for SortedKV<K, V, H>
+where
+    K: Encode + Ord + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    type K = K;
+    type V = V;
+    type LookupProof = SortedKVLookup<K, V, H>;
+    type Commitment = Digest<H>;
 
     fn new() -> Self {
         SortedKV {
-            commitment: empty_kv_hash(),
             store: vec![],
+            levels: vec![vec![]],
         }
     }
 
-    fn commit(&self) -> Digest {
-        self.commitment
+    fn commit(&self) -> Digest<H> {
+        let root = self.levels.last().and_then(|level| level.first());
+        root.copied().unwrap_or_else(empty_kv_hash)
     }
 
     fn check_proof(
@@ -141,7 +386,7 @@ impl AuthenticatedKV for SortedKV {
                         return None;
                     }
                     (_, Some(prev_proof)) => {
-                        if prev_proof.key > key {
+                        if prev_proof.key >= key {
                             return None;
                         }
                         if prev_proof.root_from_path(ix - 1) != *comm {
@@ -163,7 +408,7 @@ impl AuthenticatedKV for SortedKV {
 
                         let mut ix = *ix;
                         for sib in path_siblings.iter() {
-                            let sib_is_right = ix % 2 == 0;
+                            let sib_is_right = ix.is_multiple_of(2);
                             ix /= 2;
                             if sib_is_right && *sib != empty_kv_hash() {
                                 return None;
@@ -196,6 +441,10 @@ impl AuthenticatedKV for SortedKV {
             ) => {
                 match (next_ix, prev) {
                     (0, None) => {}
+                    // there is no entry before the first one
+                    (0, Some(_)) => {
+                        return None;
+                    }
                     (_, Some(prev_proof)) => {
                         if prev_proof.key >= key {
                             return None;
@@ -229,7 +478,7 @@ impl AuthenticatedKV for SortedKV {
                             Some(prev_proof) => {
                                 let mut ix = *next_ix - 1;
                                 for sib in prev_proof.siblings.iter() {
-                                    let sib_is_right = ix % 2 == 0;
+                                    let sib_is_right = ix.is_multiple_of(2);
                                     ix /= 2;
                                     if sib_is_right && *sib != empty_kv_hash() {
                                         return None;
@@ -281,12 +530,14 @@ impl AuthenticatedKV for SortedKV {
         let prev = if ix == 0 {
             None
         } else {
-            sortedkv_util::prove_lookup(ix - 1, &self.store)
+            sortedkv_util::prove_lookup(ix - 1, &self.store, &self.levels)
         };
 
-        let next = sortedkv_util::prove_lookup(ix + 1, &self.store);
+        let next =
+            sortedkv_util::prove_lookup(ix + 1, &self.store, &self.levels);
 
-        let ix_proof = sortedkv_util::prove_lookup(ix, &self.store).unwrap();
+        let ix_proof =
+            sortedkv_util::prove_lookup(ix, &self.store, &self.levels).unwrap();
 
         match ix_proof.key.cmp(&key) {
             Ordering::Equal => (
@@ -317,43 +568,44 @@ impl AuthenticatedKV for SortedKV {
         }
     }
 
-    fn insert(self, key: Self::K, value: Self::V) -> Self {
-        let ret = if self.store.is_empty() {
-            vec![(key, value)]
+    fn insert(mut self, key: Self::K, value: Self::V) -> Self {
+        let insert_ix = if self.store.is_empty() {
+            0
         } else {
             let ix = self.binary_search(&key);
-            let mut ret = self.store;
-            let insert_ix = if ret[ix].0 <= key { ix + 1 } else { ix };
-            ret.insert(insert_ix, (key, value));
-            ret
+            match self.store[ix].0.cmp(&key) {
+                Ordering::Equal => {
+                    // the entry stays at its index, so only its path to
+                    // the root changes
+                    self.store[ix].1 = value;
+                    sortedkv_util::rehash_leaf(
+                        &mut self.levels,
+                        &self.store,
+                        ix,
+                    );
+                    return self;
+                }
+                Ordering::Less => ix + 1,
+                Ordering::Greater => ix,
+            }
         };
+        self.store.insert(insert_ix, (key, value));
 
-        Self {
-            commitment: sortedkv_util::merkle_hash_arr(
-                ret.iter().map(|(x, y)| (x, y)),
-            ),
-            store: ret,
-        }
+        sortedkv_util::rehash_from(&mut self.levels, &self.store, insert_ix);
+        self
     }
 
-    fn remove(self, key: Self::K) -> Self {
-        let ret = if self.store.is_empty() {
-            vec![]
-        } else {
-            let ix = self.binary_search(&key);
-            let mut ret = self.store;
-            if ret[ix].0 == key {
-                ret.remove(ix);
-            }
-            ret
-        };
+    fn remove(mut self, key: Self::K) -> Self {
+        if self.store.is_empty() {
+            return self;
+        }
 
-        Self {
-            commitment: sortedkv_util::merkle_hash_arr(
-                ret.iter().map(|(x, y)| (x, y)),
-            ),
-            store: ret,
+        let ix = self.binary_search(&key);
+        if self.store[ix].0 == key {
+            self.store.remove(ix);
+            sortedkv_util::rehash_from(&mut self.levels, &self.store, ix);
         }
+        self
     }
 }
 
@@ -361,26 +613,31 @@ impl AuthenticatedKV for SortedKV {
 // correct (though there may still be bugs!)
 mod sortedkv_util {
     use super::*;
-    pub fn hash_kv(k: &str, v: &str) -> Digest {
-        common::hash_two_things("hash_kv_K", "hash_kv_V", k, v)
+    pub fn hash_kv<K: Encode, V: Encode, H: Hasher>(k: &K, v: &V) -> Digest<H> {
+        common::hash_two_things(
+            "hash_kv_K",
+            "hash_kv_V",
+            k.encode(),
+            v.encode(),
+        )
     }
 
-    pub fn hash_branch(l: Digest, r: Digest) -> Digest {
+    pub fn hash_branch<H: Hasher>(l: Digest<H>, r: Digest<H>) -> Digest<H> {
         common::hash_two_things("hash_branch_L", "hash_branch_R", l, r)
     }
 
     /// Calculate the overall hash of a merkle tree which has `(k,v)` stored
     /// at leaf position `ix`, with sibling hashes `path`.
-    fn root_from_path(
+    fn root_from_path<K: Encode, V: Encode, H: Hasher>(
         mut ix: usize,
-        path: &[Digest],
-        k: &str,
-        v: &str,
-    ) -> Digest {
+        path: &[Digest<H>],
+        k: &K,
+        v: &V,
+    ) -> Digest<H> {
         let mut running_hash = hash_kv(k, v);
 
         for sib in path.iter() {
-            let sib_is_right = ix % 2 == 0;
+            let sib_is_right = ix.is_multiple_of(2);
             ix /= 2;
             let (l, r) = if sib_is_right {
                 (running_hash, *sib)
@@ -392,7 +649,7 @@ mod sortedkv_util {
 
         // this case is never used in SortedKV
         while ix > 0 {
-            let sib_is_right = ix % 2 == 0;
+            let sib_is_right = ix.is_multiple_of(2);
             ix /= 2;
             let (l, r) = if sib_is_right {
                 (running_hash, empty_kv_hash())
@@ -443,16 +700,21 @@ mod sortedkv_util {
      *      e = H_branch(c,EMPTY)
      *      f = H_branch(d,e)
      *  ```
+     *
+     *  `SortedKV` keeps the hashes of this tree instead (see
+     *  `rehash_from`), so this is only used by the tests, as the
+     *  definition they are checked against.
      */
-    pub fn merkle_hash_arr<S: AsRef<str> + core::fmt::Debug>(
-        arr: impl Iterator<Item = (S, S)>,
-    ) -> Digest {
+    #[cfg(test)]
+    pub fn merkle_hash_arr<K: Encode, V: Encode, H: Hasher>(
+        arr: impl Iterator<Item = (K, V)>,
+    ) -> Digest<H> {
         let mut peaks = vec![None];
         for (count, (k, v)) in arr.enumerate() {
             for (i, p) in peaks.iter().enumerate() {
                 assert_eq!(((count >> i) & 1) == 0, p.is_none());
             }
-            let mut running_hash = hash_kv(k.as_ref(), v.as_ref());
+            let mut running_hash = hash_kv(&k, &v);
             let mut i = 0;
             while let Some(sib_hash) = peaks[i] {
                 running_hash = hash_branch(sib_hash, running_hash);
@@ -497,87 +759,194 @@ mod sortedkv_util {
         }
     }
 
-    #[derive(Debug, Clone)]
-    pub struct MerkleLookupPath {
-        pub key: String,
-        pub value: String,
-        pub siblings: Vec<Digest>,
+    /// Bring `levels`, the hashes of the tree `merkle_hash_arr` builds over
+    /// `arr`, up to date after every entry from index `ix` on changed.
+    ///
+    /// A subtree covering only entries before `ix` keeps its hash, so this
+    /// rehashes about `2 * (arr.len() - ix)` nodes.
+    pub fn rehash_from<K: Encode, V: Encode, H: Hasher>(
+        levels: &mut Vec<Vec<Digest<H>>>,
+        arr: &[(K, V)],
+        ix: usize,
+    ) {
+        if levels.is_empty() {
+            levels.push(vec![]);
+        }
+        levels[0].truncate(ix);
+        levels[0].extend(arr[ix..].iter().map(|(k, v)| hash_kv(k, v)));
+
+        // a pair missing its right half has an empty subtree there, and the
+        // level with a single hash is the root
+        let mut height = 0;
+        while levels[height].len() > 1 {
+            if levels.len() == height + 1 {
+                levels.push(vec![]);
+            }
+            let (below, above) = levels.split_at_mut(height + 1);
+            let (below, above) = (&below[height], &mut above[0]);
+
+            let start = (ix >> (height + 1)).min(above.len());
+            above.truncate(start);
+            above.extend(below[2 * start..].chunks(2).map(|pair| {
+                let right = pair.get(1).copied().unwrap_or_else(empty_kv_hash);
+                hash_branch(pair[0], right)
+            }));
+            height += 1;
+        }
+        levels.truncate(height + 1);
     }
 
-    impl MerkleLookupPath {
-        pub fn root_from_path(&self, ix: usize) -> Digest {
-            root_from_path(ix, &self.siblings, &self.key, &self.value)
+    /// Bring `levels` up to date after only the entry at index `ix` of
+    /// `arr` changed, by rehashing its path to the root.
+    pub fn rehash_leaf<K: Encode, V: Encode, H: Hasher>(
+        levels: &mut [Vec<Digest<H>>],
+        arr: &[(K, V)],
+        ix: usize,
+    ) {
+        let (k, v) = &arr[ix];
+        levels[0][ix] = hash_kv(k, v);
+
+        for height in 1..levels.len() {
+            let (below, above) = levels.split_at_mut(height);
+            let below = &below[height - 1];
+            let node = ix >> height;
+            let right = below
+                .get(2 * node + 1)
+                .copied()
+                .unwrap_or_else(empty_kv_hash);
+            above[0][node] = hash_branch(below[2 * node], right);
         }
     }
 
-    /// Authenticated lookup of `arr[i]`.
-    ///
-    /// This is some of the most subtle code involved in this data
-    /// structure, since we aren't storing the merkle tree itself in
-    /// memory. If we had the merkle tree in memory, the operations would
-    /// be a lot clearer and would not involve the kind of complicated
-    /// index manipulation you see here. So far, 1 person has passed the
-    /// "find the bug" task by discovering that a prior version of this
-    /// function was broken. That said, the intended "find the bug" bug
-    /// should be easier to find than poking every edge case of this
-    /// function.
-    pub fn prove_lookup(
-        i: usize,
-        arr: &[(String, String)],
-    ) -> Option<MerkleLookupPath> {
-        arr.get(i).cloned().map(|(k, v)| {
-            let mut sib_height = 0;
-            let mut sibs = vec![];
-            while (1 << sib_height) < arr.len() {
-                let sib_is_right = ((i >> sib_height) & 1) == 0;
-
-                let sib_hash = if sib_is_right {
-                    let sib_lo = ((i >> sib_height) + 1) << sib_height;
-                    let sib_hi = sib_lo + (1 << sib_height);
-
-                    if sib_hi <= arr.len() {
-                        merkle_hash_arr(arr[sib_lo..sib_hi].iter().cloned())
-                    } else if sib_lo >= arr.len() {
-                        empty_kv_hash()
-                    } else {
-                        // in this case, `merkle_hash_arr` doesn't capture
-                        // the "whole sibling tree"
-                        let mut ret =
-                            merkle_hash_arr(arr[sib_lo..].iter().cloned());
-                        let sib_arr_height = {
-                            let mut h = 0;
-                            while (1 << h) < arr.len() - sib_lo {
-                                h += 1;
-                            }
-                            h
-                        };
+    /// The siblings of the nodes covering `arr[first..end]` at each level
+    /// below the root, where `levels` are the hashes of the tree over `arr`.
+    pub fn prove_range<H: Hasher>(
+        first: usize,
+        end: usize,
+        levels: &[Vec<Digest<H>>],
+    ) -> Vec<RangeSiblings<H>> {
+        if first == end {
+            return vec![];
+        }
 
-                        // fill out the sibling tree with empty subtrees to
-                        // make it the correct height
-                        for _ in sib_arr_height..sib_height {
-                            ret = hash_branch(ret, empty_kv_hash());
-                        }
-                        ret
-                    }
+        let heights = levels.len() - 1;
+        levels[..heights]
+            .iter()
+            .enumerate()
+            .map(|(height, level)| {
+                let (lo, hi) = (first >> height, (end - 1) >> height);
+                let left = if lo % 2 == 1 {
+                    Some(level[lo - 1])
+                } else {
+                    None
+                };
+                let right = if hi % 2 == 0 {
+                    Some(
+                        level
+                            .get(hi + 1)
+                            .copied()
+                            .unwrap_or_else(empty_kv_hash),
+                    )
                 } else {
-                    let sib_hi = (i >> sib_height) << sib_height;
-                    let sib_lo = sib_hi - (1 << sib_height);
-                    merkle_hash_arr(arr[sib_lo..sib_hi].iter().cloned())
+                    None
                 };
-                sibs.push(sib_hash);
+                (left, right)
+            })
+            .collect()
+    }
+
+    /// Calculate the overall hash of a merkle tree whose leaves from
+    /// position `first` on have the hashes `leaves`, with the sibling
+    /// hashes `siblings` from `prove_range`. If `at_end` is set, the leaves
+    /// must be the last ones in the tree.
+    ///
+    /// Returns `None` if `siblings` doesn't have exactly the siblings the
+    /// leaves need.
+    pub fn root_from_range<H: Hasher>(
+        mut first: usize,
+        mut hashes: Vec<Digest<H>>,
+        siblings: &[RangeSiblings<H>],
+        at_end: bool,
+    ) -> Option<Digest<H>> {
+        if hashes.is_empty() {
+            // only the empty list has no leaves to cover
+            return (first == 0 && siblings.is_empty()).then(empty_kv_hash);
+        }
 
-                sib_height += 1;
+        for (left, right) in siblings.iter() {
+            // pad the covered nodes out to whole pairs
+            match (first % 2 == 1, left) {
+                (true, Some(left)) => {
+                    hashes.insert(0, *left);
+                    first -= 1;
+                }
+                (false, None) => {}
+                _ => return None,
+            }
+            match (hashes.len() % 2 == 1, right) {
+                (true, Some(right)) => {
+                    if at_end && *right != empty_kv_hash() {
+                        return None;
+                    }
+                    hashes.push(*right);
+                }
+                (false, None) => {}
+                _ => return None,
             }
 
-            assert_eq!(
-                root_from_path(i, &sibs, &k, &v),
-                merkle_hash_arr(arr.iter().cloned())
-            );
+            hashes = hashes
+                .chunks(2)
+                .map(|pair| hash_branch(pair[0], pair[1]))
+                .collect();
+            first /= 2;
+        }
+
+        if first != 0 || hashes.len() != 1 {
+            return None;
+        }
+
+        Some(hashes[0])
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct MerkleLookupPath<K = String, V = String, H: Hasher = Sha256> {
+        pub key: K,
+        pub value: V,
+        pub siblings: Vec<Digest<H>>,
+    }
+
+    impl<K: Encode, V: Encode, H: Hasher> MerkleLookupPath<K, V, H> {
+        pub fn root_from_path(&self, ix: usize) -> Digest<H> {
+            root_from_path(ix, &self.siblings, &self.key, &self.value)
+        }
+    }
+
+    /// Authenticated lookup of `arr[i]`, where `levels` are the hashes of
+    /// the tree over `arr`, as kept up to date by `rehash_from`.
+    ///
+    /// The sibling at each height is read from `levels`. A sibling past the
+    /// end of its level covers no entries, so it is an empty subtree.
+    pub fn prove_lookup<K: Encode + Clone, V: Encode + Clone, H: Hasher>(
+        i: usize,
+        arr: &[(K, V)],
+        levels: &[Vec<Digest<H>>],
+    ) -> Option<MerkleLookupPath<K, V, H>> {
+        arr.get(i).cloned().map(|(k, v)| {
+            // the top level is the root, which has no sibling
+            let heights = levels.len().saturating_sub(1);
+            let siblings = levels[..heights]
+                .iter()
+                .enumerate()
+                .map(|(height, level)| {
+                    let sib_ix = (i >> height) ^ 1;
+                    level.get(sib_ix).copied().unwrap_or_else(empty_kv_hash)
+                })
+                .collect();
 
             MerkleLookupPath {
                 key: k,
                 value: v,
-                siblings: sibs,
+                siblings,
             }
         })
     }
@@ -596,6 +965,34 @@ pub mod tests {
         Remove(String),
     }
 
+    /// Entries with binary keys and values. Both keys are invalid UTF-8,
+    /// and would be the same key after a lossy conversion.
+    pub fn binary_entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![(vec![0xff], vec![0x80, 0]), (vec![0xfe], vec![])]
+    }
+
+    /// Insert `binary_entries()` into `kv`, and check that each of them and
+    /// a missing key are looked up and proven correctly.
+    pub fn check_binary_keys_and_values<KV>(kv: KV)
+    where
+        KV: AuthenticatedKV<K = Vec<u8>, V = Vec<u8>>,
+    {
+        let entries = binary_entries();
+        let kv = entries
+            .iter()
+            .cloned()
+            .fold(kv, |kv, (k, v)| kv.insert(k, v));
+
+        for (k, v) in entries.iter() {
+            let (val, proof) = kv.get(k.clone());
+            assert_eq!(val.as_ref(), Some(v));
+            KV::check_proof(k.clone(), val, &proof, &kv.commit()).unwrap();
+        }
+        let (val, proof) = kv.get(vec![0xfd]);
+        assert_eq!(val, None);
+        KV::check_proof(vec![0xfd], val, &proof, &kv.commit()).unwrap();
+    }
+
     fn hash_btree_insert_get_remove(ops: Vec<InsertGetRemoveOp>) {
         let mut hmap = HashMap::new();
         let mut bmap = BTreeMap::new();
@@ -652,7 +1049,7 @@ pub mod tests {
      */
     fn hash_sortedkv_insert_get_remove(ops: Vec<InsertGetRemoveOp>) {
         let mut hmap = HashMap::new();
-        let mut sorted_kv = SortedKV::new();
+        let mut sorted_kv = <SortedKV>::new();
 
         for op in ops {
             match op {
@@ -662,7 +1059,7 @@ pub mod tests {
                 }
                 InsertGetRemoveOp::Get(k) => {
                     let (val, proof) = sorted_kv.get(k.clone());
-                    SortedKV::check_proof(
+                    <SortedKV>::check_proof(
                         k.clone(),
                         val.clone(),
                         &proof,
@@ -676,10 +1073,27 @@ pub mod tests {
                     sorted_kv = sorted_kv.remove(k.clone());
                 }
             }
+
+            // the kept hashes give the commitment of the whole store
+            let store = sorted_kv.store.iter().map(|(k, v)| (k, v));
+            assert_eq!(
+                sorted_kv.commit(),
+                sortedkv_util::merkle_hash_arr(store)
+            );
+            assert!(sorted_kv.store.windows(2).all(|w| w[0].0 < w[1].0));
+            assert_eq!(sorted_kv.store.len(), hmap.len());
         }
+
+        // the commitment only depends on the entries left, not on the
+        // writes that produced them
+        let mut entries: Vec<_> = hmap.into_iter().collect();
+        entries.sort();
+        let fresh = entries
+            .into_iter()
+            .fold(<SortedKV>::new(), |kv, (k, v)| kv.insert(k, v));
+        assert_eq!(sorted_kv.commit(), fresh.commit());
     }
 
-    // NOTE: This test might fail as operations include remove as well in arbitrary implemetation for InsertGetRemoveOp
     #[quickcheck]
     fn hash_sortedkv_insert_get_quickcheck(ops: Vec<InsertGetRemoveOp>) {
         hash_sortedkv_insert_get_remove(ops);
@@ -723,6 +1137,325 @@ pub mod tests {
         ]);
     }
 
+    // the tree grows and shrinks a level at each power of two
+    #[test]
+    fn hash_sortedkv_levels_track_size() {
+        let expected = |kv: &SortedKV| {
+            sortedkv_util::merkle_hash_arr(kv.store.iter().map(|(k, v)| (k, v)))
+        };
+
+        let mut kv = <SortedKV>::new();
+        for i in 0..70 {
+            kv = kv.insert(format!("{:03}", i), "".to_string());
+            assert_eq!(kv.commit(), expected(&kv));
+        }
+        for i in (0..70).step_by(3) {
+            kv = kv.insert(format!("{:03}", i), "again".to_string());
+            assert_eq!(kv.commit(), expected(&kv));
+        }
+        assert_eq!(kv.store.len(), 70);
+        while let Some((k, _)) = kv.store.first().cloned() {
+            kv = kv.remove(k);
+            assert_eq!(kv.commit(), expected(&kv));
+        }
+        assert_eq!(kv.commit(), empty_kv_hash());
+        assert_eq!(kv.levels, vec![vec![]]);
+    }
+
+    #[test]
+    fn hash_sortedkv_history_independent() {
+        let forward = (0..10).fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(i.to_string(), "old".to_string())
+        });
+        let forward = (0..10)
+            .fold(forward, |kv, i| kv.insert(i.to_string(), i.to_string()));
+        let backward = (0..12).rev().fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(i.to_string(), i.to_string())
+        });
+        let backward = backward
+            .remove("10".to_string())
+            .remove("11".to_string())
+            .remove("11".to_string());
+        assert_eq!(forward.store, backward.store);
+        assert_eq!(forward.commit(), backward.commit());
+    }
+
+    // a list with a repeated key can't prove a lookup of that key
+    #[test]
+    fn hash_sortedkv_rejects_repeated_keys() {
+        let mut kv = <SortedKV>::new();
+        kv.store = vec![
+            ("0".to_string(), "a".to_string()),
+            ("0".to_string(), "b".to_string()),
+            ("1".to_string(), "c".to_string()),
+        ];
+        sortedkv_util::rehash_from(&mut kv.levels, &kv.store, 0);
+
+        let (val, proof) = kv.get("0".to_string());
+        assert_eq!(val.as_deref(), Some("b"));
+        assert!(<SortedKV>::check_proof(
+            "0".to_string(),
+            val,
+            &proof,
+            &kv.commit()
+        )
+        .is_none());
+
+        let (a, b) = ("0".to_string(), "1".to_string());
+        let (entries, proof) = kv.get_range(&a, &b);
+        assert_eq!(entries.len(), 2);
+        assert!(<SortedKV>::check_range_proof(
+            &a,
+            &b,
+            &entries,
+            &proof,
+            &kv.commit()
+        )
+        .is_none());
+    }
+
+    // a proof decoded from bytes can claim an entry before the start of
+    // the list, which must be rejected rather than panic
+    #[test]
+    fn hash_sortedkv_rejects_entry_before_start() {
+        let kv = (1..4).fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(i.to_string(), i.to_string())
+        });
+        let (_, proof) = kv.get("0".to_string());
+        let next = match proof {
+            SortedKVLookup::NotPresent {
+                next_ix: 0, next, ..
+            } => next,
+            _ => panic!("0 comes before every key"),
+        };
+        let forged: SortedKVLookup = SortedKVLookup::NotPresent {
+            next_ix: 0,
+            prev: next.clone(),
+            next,
+        };
+        let forged: SortedKVLookup =
+            crate::codec::decode(&crate::codec::encode(&forged)).unwrap();
+        assert!(<SortedKV>::check_proof(
+            "0".to_string(),
+            None,
+            &forged,
+            &kv.commit()
+        )
+        .is_none());
+    }
+
+    // bulk loading gives the list that inserting the entries one by one
+    // does, with the same kept hashes
+    #[quickcheck]
+    fn hash_sortedkv_bulk_load_quickcheck(
+        first: Vec<(u8, u8)>,
+        second: Vec<(u8, u8)>,
+    ) {
+        let widen = |entries: Vec<(u8, u8)>| -> Vec<(u64, u64)> {
+            entries
+                .iter()
+                .map(|(k, v)| (*k as u64, *v as u64))
+                .collect()
+        };
+        let (first, second) = (widen(first), widen(second));
+        let all = || first.iter().chain(second.iter()).cloned();
+
+        let inserted =
+            all().fold(SortedKV::<_, _>::new(), |kv, (k, v)| kv.insert(k, v));
+        let check = |kv: &SortedKV<u64, u64>| {
+            assert_eq!(kv.store, inserted.store);
+            assert_eq!(kv.levels, inserted.levels);
+        };
+
+        let bmap: BTreeMap<_, _> = all().collect();
+        check(&SortedKV::from_sorted_iter(bmap).unwrap());
+        check(&all().collect());
+
+        let mut extended: SortedKV<_, _> = first.iter().cloned().collect();
+        extended.extend(second.iter().cloned());
+        check(&extended);
+    }
+
+    #[test]
+    fn hash_sortedkv_bulk_load_test_cases() {
+        let from_sorted = |keys: &[u64]| {
+            SortedKV::<_, _>::from_sorted_iter(keys.iter().map(|k| (*k, *k)))
+        };
+
+        let kv = from_sorted(&[]).unwrap();
+        assert_eq!(kv.commit(), empty_kv_hash());
+        assert_eq!(kv.levels, vec![vec![]]);
+        let kv = from_sorted(&[1, 2, 5, 9, 10]).unwrap();
+        assert_eq!(kv.store.len(), 5);
+
+        assert_eq!(
+            from_sorted(&[1, 2, 2, 3]).unwrap_err(),
+            FromSortedIterError::DuplicateKey { index: 2 }
+        );
+        assert_eq!(
+            from_sorted(&[1, 3, 2, 2]).unwrap_err(),
+            FromSortedIterError::OutOfOrder { index: 2 }
+        );
+
+        // the last value of a repeated key is kept, whether the key is new
+        // or already in the list
+        let mut kv: SortedKV<u64, u64> =
+            vec![(3, 10), (1, 11), (3, 12)].into_iter().collect();
+        assert_eq!(kv.store, vec![(1, 11), (3, 12)]);
+        kv.extend(vec![(2, 13), (1, 14), (2, 15), (0, 16)]);
+        assert_eq!(kv.store, vec![(0, 16), (1, 14), (2, 15), (3, 12)]);
+        kv.extend(vec![]);
+        assert_eq!(kv.store.len(), 4);
+        assert_eq!(
+            kv.commit(),
+            sortedkv_util::merkle_hash_arr(
+                kv.store.iter().map(|(k, v)| (k, v))
+            )
+        );
+    }
+
+    #[quickcheck]
+    fn hash_sortedkv_range_quickcheck(keys: Vec<u8>, a: u8, b: u8) {
+        let bmap: BTreeMap<_, _> =
+            keys.iter().map(|k| (*k, format!("v{}", k))).collect();
+        let kv = bmap.iter().fold(SortedKV::<_, _>::new(), |kv, (k, v)| {
+            kv.insert(vec![*k], v.clone())
+        });
+        let (a, b) = (vec![a], vec![b]);
+
+        let (entries, proof) = kv.get_range(&a, &b);
+        let expected: Vec<_> = bmap
+            .iter()
+            .filter(|(k, _)| a[0] <= **k && **k < b[0])
+            .map(|(k, v)| (vec![*k], v.clone()))
+            .collect();
+        assert_eq!(entries, expected);
+        SortedKV::check_range_proof(&a, &b, &entries, &proof, &kv.commit())
+            .unwrap();
+
+        // leaving out any entry is caught
+        for i in 0..entries.len() {
+            let mut fewer = entries.clone();
+            fewer.remove(i);
+            assert!(SortedKV::check_range_proof(
+                &a,
+                &b,
+                &fewer,
+                &proof,
+                &kv.commit()
+            )
+            .is_none());
+        }
+    }
+
+    #[test]
+    fn hash_sortedkv_range_test_cases() {
+        let kv = (0..20).fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(format!("{:02}", i), i.to_string())
+        });
+        let comm = kv.commit();
+        let check = |a: &str,
+                     b: &str,
+                     entries: &[(String, String)],
+                     pf: &SortedKVRangeProof| {
+            <SortedKV>::check_range_proof(
+                &a.to_string(),
+                &b.to_string(),
+                entries,
+                pf,
+                &comm,
+            )
+        };
+
+        let (entries, proof) =
+            kv.get_range(&"05".to_string(), &"09".to_string());
+        assert_eq!(entries.len(), 4);
+        assert_eq!(proof.start, 5);
+        check("05", "09", &entries, &proof).unwrap();
+
+        // the proof doesn't cover a wider range, or another commitment
+        assert!(check("04", "09", &entries, &proof).is_none());
+        assert!(check("05", "10", &entries, &proof).is_none());
+        assert!(<SortedKV>::check_range_proof(
+            &"05".to_string(),
+            &"09".to_string(),
+            &entries,
+            &proof,
+            &<SortedKV>::new().commit(),
+        )
+        .is_none());
+
+        // the ends of the list can't be claimed in the middle of it
+        let mut no_next = proof.clone();
+        no_next.next = None;
+        assert!(check("05", "09", &entries, &no_next).is_none());
+        let mut no_prev = proof.clone();
+        no_prev.prev = None;
+        assert!(check("05", "09", &entries, &no_prev).is_none());
+
+        // nor can an entry before the start of the list
+        let (first_entries, mut before_start) =
+            kv.get_range(&"".to_string(), &"03".to_string());
+        before_start.prev = Some(("".to_string(), "".to_string()));
+        assert!(check("0", "03", &first_entries, &before_start).is_none());
+
+        // entries out of order are rejected
+        let mut swapped = entries.clone();
+        swapped.swap(0, 1);
+        assert!(check("05", "09", &swapped, &proof).is_none());
+
+        // a range at either end of the list, the whole list and an empty range
+        for (a, b, len) in [
+            ("", "03", 3),
+            ("17", "~", 3),
+            ("", "~", 20),
+            ("055", "06", 0),
+            ("30", "40", 0),
+        ]
+        .iter()
+        {
+            let (entries, proof) = kv.get_range(&a.to_string(), &b.to_string());
+            assert_eq!(entries.len(), *len);
+            check(a, b, &entries, &proof).unwrap();
+        }
+
+        let empty = <SortedKV>::new();
+        let (entries, proof) =
+            empty.get_range(&"".to_string(), &"~".to_string());
+        assert!(entries.is_empty());
+        <SortedKV>::check_range_proof(
+            &"".to_string(),
+            &"~".to_string(),
+            &entries,
+            &proof,
+            &empty.commit(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn hash_sortedkv_binary_keys_and_values() {
+        check_binary_keys_and_values(SortedKV::<_, _>::new());
+    }
+
+    #[test]
+    fn hash_sortedkv_other_hasher() {
+        let kv = (0..10).fold(
+            SortedKV::<String, String, common::Sha512_256>::new(),
+            |kv, i| kv.insert(i.to_string(), i.to_string()),
+        );
+        for k in ["0", "9", "10"] {
+            let (val, proof) = kv.get(k.to_string());
+            SortedKV::check_proof(k.to_string(), val, &proof, &kv.commit())
+                .unwrap();
+        }
+
+        let sha256 = (0..10).fold(<SortedKV>::new(), |kv, i| {
+            kv.insert(i.to_string(), i.to_string())
+        });
+        assert_ne!(sha256.commit().as_ref(), kv.commit().as_ref());
+    }
+
     #[test]
     /*
      *  *******************************************
@@ -733,7 +1466,7 @@ pub mod tests {
      * while removing as well, the rightmost duplicate key will be removed. So previous key still exists.
      * In the following test duplicate key is inserted, removed and queried for it.
      *
-     * To overcome this, we can replace the key if it exists already.
+     * To overcome this, insert now replaces the key if it exists already.
      */
     fn find_the_bug() {
         use InsertGetRemoveOp::*;
@@ -789,20 +1522,25 @@ pub mod tests {
                     )
                 }
                 InsertGetRemoveOp::Get(k) => {
-                    Box::new(k.shrink().map(|k| InsertGetRemoveOp::Get(k)))
+                    Box::new(k.shrink().map(InsertGetRemoveOp::Get))
+                }
+                InsertGetRemoveOp::Remove(k) => {
+                    Box::new(k.shrink().map(InsertGetRemoveOp::Remove))
                 }
-                _ => todo!(),
             }
         }
     }
 
     #[quickcheck]
     fn utils_check(arr: Vec<(String, String)>, lookups: Vec<usize>) {
-        let root_hash =
+        let root_hash: Digest =
             sortedkv_util::merkle_hash_arr(arr.iter().map(|(x, y)| (x, y)));
+        let mut levels = vec![];
+        sortedkv_util::rehash_from(&mut levels, &arr, 0);
 
         for i in lookups {
-            match (arr.get(i).cloned(), sortedkv_util::prove_lookup(i, &arr)) {
+            let proof = sortedkv_util::prove_lookup(i, &arr, &levels);
+            match (arr.get(i).cloned(), proof) {
                 (None, None) => {}
                 (Some((k1, v1)), Some(proof)) => {
                     assert_eq!(k1, proof.key);
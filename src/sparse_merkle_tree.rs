@@ -1,7 +1,20 @@
+pub use self::smt_util::empty_hash;
 use self::smt_util::hash_branch;
+use self::smt_util::EmptyHashes;
 use crate::common::*;
 use crate::kv_trait::AuthenticatedKV;
+use crate::node_store::{MemoryNodeStore, NodeStore, StoredNode};
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+
+/// The depth of the tree, one level per bit of `hash_key`.
+pub const TREE_DEPTH: usize = 256;
+
+// the most threads `build_parallel` uses, whatever it is asked for
+const MAX_BUILD_THREADS: usize = 256;
 
 /*
  *  *******************************************
@@ -9,179 +22,1617 @@ use std::collections::HashMap;
  *  *******************************************
  */
 
-mod smt_util {
-    use super::*;
-    pub fn hash_key(k: &str) -> Digest {
-        hash_one_thing("hash_key", k)
+pub(crate) mod smt_util {
+    use super::*;
+    pub fn hash_key<K: Encode + ?Sized, H: Hasher>(k: &K) -> Digest<H> {
+        hash_one_thing("hash_key", k.encode())
+    }
+
+    pub fn hash_value<V: Encode + ?Sized, H: Hasher>(v: &V) -> Digest<H> {
+        hash_one_thing("hash_value", v.encode())
+    }
+
+    // hash_leaf commits to the hashed key and the hashed value, so a leaf can be
+    // opened in a non-membership proof without revealing the original key or value.
+    pub fn hash_leaf<H: Hasher>(h_k: Digest<H>, h_v: Digest<H>) -> Digest<H> {
+        hash_two_things("hash_leaf_K", "hash_leaf_V", h_k, h_v)
+    }
+
+    pub fn hash_kv<K: Encode + ?Sized, V: Encode + ?Sized, H: Hasher>(
+        k: &K,
+        v: &V,
+    ) -> Digest<H> {
+        hash_leaf(hash_key(k), hash_value(v))
+    }
+
+    pub fn hash_branch<H: Hasher>(l: Digest<H>, r: Digest<H>) -> Digest<H> {
+        hash_two_things("hash_branch_L", "hash_branch_R", l, r)
+    }
+
+    /// The digest of an empty subtree of the given height, where height 0
+    /// is the leaf level and height `TREE_DEPTH` is the whole tree. Each
+    /// level is `hash_branch` of two copies of the level below, so it can't
+    /// be mistaken for the digest of a subtree holding any leaf.
+    pub fn empty_hash<H: Hasher>(height: usize) -> Digest<H> {
+        EmptyHashes::get().height(height)
+    }
+
+    // EmptyHashes is the table of empty_hash for every height, built the first time
+    // a hash function asks for it and kept for the rest of the program, one table
+    // per hash function. Finding the table takes a lock, so each operation gets it
+    // once and passes it down to wherever an empty subtree is hashed.
+    pub struct EmptyHashes<H: Hasher>(&'static [Digest<H>]);
+
+    impl<H: Hasher> Clone for EmptyHashes<H> {
+        fn clone(&self) -> Self {
+            *self
+        }
+    }
+
+    impl<H: Hasher> Copy for EmptyHashes<H> {}
+
+    impl<H: Hasher> std::fmt::Debug for EmptyHashes<H> {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("EmptyHashes")
+        }
+    }
+
+    impl<H: Hasher> EmptyHashes<H> {
+        pub fn get() -> Self {
+            type Tables =
+                RwLock<HashMap<TypeId, &'static (dyn Any + Send + Sync)>>;
+            static TABLES: OnceLock<Tables> = OnceLock::new();
+
+            let tables = TABLES.get_or_init(Default::default);
+            let id = TypeId::of::<H>();
+            let found = tables.read().unwrap().get(&id).copied();
+            let table = found.unwrap_or_else(|| {
+                *tables.write().unwrap().entry(id).or_insert_with(|| {
+                    let mut hashes: Vec<Digest<H>> =
+                        vec![hash_one_thing("empty_leaf", b"")];
+                    for h in 0..TREE_DEPTH {
+                        hashes.push(hash_branch(hashes[h], hashes[h]));
+                    }
+                    Box::leak(Box::new(hashes))
+                })
+            });
+            EmptyHashes(table.downcast_ref::<Vec<Digest<H>>>().unwrap())
+        }
+
+        pub fn height(self, height: usize) -> Digest<H> {
+            self.0[height]
+        }
+
+        // the digest of an empty subtree rooted at `depth` below the root.
+        pub fn at(self, depth: usize) -> Digest<H> {
+            self.0[TREE_DEPTH - depth]
+        }
+    }
+
+    // root_from_path takes siblings along the path from leaf to merkle root
+    // it calculates the digest of the leaf and check's the branch node is left node
+    // or right node based on bitstring and then hashes it appropriately untill root node is calculated.
+    pub fn root_from_path<K, V, H>(
+        path: &CompressedPath<H>,
+        k: &K,
+        v: &V,
+    ) -> Option<Digest<H>>
+    where
+        K: Encode + ?Sized,
+        V: Encode + ?Sized,
+        H: Hasher,
+    {
+        let h_k = KeyPath::from(hash_key::<K, H>(k));
+        root_from_siblings(hash_kv(k, v), &h_k, path)
+    }
+
+    // root_from_siblings hashes `node` (the subtree at depth `path.depth()` on the
+    // path of `h_k`) up to the merkle root. The path is expanded on the fly:
+    // levels which aren't marked in the bitmap take the empty subtree digest for that depth.
+    // Returns `None` if the path isn't in the canonical form produced by `CompressedPath::compress`.
+    pub fn root_from_siblings<H: Hasher>(
+        node: Digest<H>,
+        h_k: &KeyPath,
+        path: &CompressedPath<H>,
+    ) -> Option<Digest<H>> {
+        if path.depth > TREE_DEPTH
+            || (path.depth..TREE_DEPTH).any(|d| path.is_set(d + 1))
+        {
+            return None;
+        }
+
+        let empty = EmptyHashes::get();
+        let mut running_hash = node;
+        let mut stored = path.siblings.iter();
+
+        for d in (1..=path.depth).rev() {
+            let sib = if path.is_set(d) {
+                let sib = *stored.next()?;
+                // a default sibling must be omitted
+                if sib == empty.at(d) {
+                    return None;
+                }
+                sib
+            } else {
+                empty.at(d)
+            };
+
+            if !h_k.bit(d - 1) {
+                // if node is on left then sibling should be on the right
+                running_hash = hash_branch(running_hash, sib)
+            } else {
+                running_hash = hash_branch(sib, running_hash)
+            }
+        }
+
+        if stored.next().is_some() {
+            return None;
+        }
+
+        Some(running_hash)
+    }
+}
+
+/// The path of a hashed key from the root, one bit per level with `false`
+/// for left and `true` for right, most significant bit of the digest first.
+///
+/// Paths are ordered like the bitstrings they spell, so sorting keys by
+/// path sorts them in the order their leaves appear in the tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyPath([u8; TREE_DEPTH / 8]);
+
+impl KeyPath {
+    /// The direction taken at depth `i`.
+    pub fn bit(&self, i: usize) -> bool {
+        self.0[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+
+    /// The number of leading bits this path shares with `other`.
+    pub fn common_prefix_len(&self, other: &KeyPath) -> usize {
+        match self.0.iter().zip(other.0.iter()).position(|(a, b)| a != b) {
+            Some(i) => {
+                8 * i + (self.0[i] ^ other.0[i]).leading_zeros() as usize
+            }
+            None => TREE_DEPTH,
+        }
+    }
+
+    /// Whether the first `len` bits of this path are those of `prefix`.
+    pub fn starts_with(&self, prefix: &KeyPath, len: usize) -> bool {
+        self.common_prefix_len(prefix) >= len
+    }
+
+    /// This path with the bit at depth `i` set to `bit`.
+    pub fn with_bit(mut self, i: usize, bit: bool) -> Self {
+        if bit {
+            self.0[i / 8] |= 0x80 >> (i % 8);
+        } else {
+            self.0[i / 8] &= !(0x80 >> (i % 8));
+        }
+        self
+    }
+}
+
+impl<H> From<Digest<H>> for KeyPath {
+    fn from(d: Digest<H>) -> Self {
+        let mut bits = [0u8; TREE_DEPTH / 8];
+        bits.copy_from_slice(d.as_ref());
+        KeyPath(bits)
+    }
+}
+
+/// The sibling hashes along a path from a node up to the root, with the
+/// siblings which are empty subtrees left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedPath<H: Hasher = Sha256> {
+    /// The depth of the node at the bottom of the path.
+    depth: usize,
+    /// Bit `d - 1` (most significant bit first) is set if the sibling at
+    /// depth `d` is not an empty subtree.
+    bitmap: [u8; TREE_DEPTH / 8],
+    /// The siblings which are not empty subtrees, ordered from the bottom
+    /// of the path to the root.
+    siblings: Vec<Digest<H>>,
+}
+
+impl<H: Hasher> CompressedPath<H> {
+    /// Compress the sibling hashes `path`, ordered from the bottom of the
+    /// path to the root.
+    pub fn compress(path: &[Digest<H>]) -> Self {
+        let empty = EmptyHashes::get();
+        let depth = path.len();
+        let mut ret = CompressedPath {
+            depth,
+            bitmap: [0; TREE_DEPTH / 8],
+            siblings: vec![],
+        };
+
+        for (i, sib) in path.iter().enumerate() {
+            let d = depth - i;
+            if *sib != empty.at(d) {
+                ret.bitmap[(d - 1) / 8] |= 0x80 >> ((d - 1) % 8);
+                ret.siblings.push(*sib);
+            }
+        }
+
+        ret
+    }
+
+    /// A path from its parts, or `None` if they don't fit together: the
+    /// depth must be at most `TREE_DEPTH`, and there must be one stored
+    /// sibling per bit set in `bitmap`.
+    pub fn from_parts(
+        depth: usize,
+        bitmap: [u8; TREE_DEPTH / 8],
+        siblings: Vec<Digest<H>>,
+    ) -> Option<Self> {
+        let set: usize = bitmap.iter().map(|b| b.count_ones() as usize).sum();
+        if depth > TREE_DEPTH || siblings.len() != set {
+            return None;
+        }
+
+        Some(CompressedPath {
+            depth,
+            bitmap,
+            siblings,
+        })
+    }
+
+    /// Expand back into every sibling hash, ordered from the bottom of the
+    /// path to the root.
+    pub fn decompress(&self) -> Vec<Digest<H>> {
+        let empty = EmptyHashes::get();
+        let mut stored = self.siblings.iter();
+        (1..=self.depth)
+            .rev()
+            .map(|d| {
+                if self.is_set(d) {
+                    stored.next().copied().unwrap_or_else(zero_digest)
+                } else {
+                    empty.at(d)
+                }
+            })
+            .collect()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn bitmap(&self) -> &[u8; TREE_DEPTH / 8] {
+        &self.bitmap
+    }
+
+    /// The siblings which are not empty subtrees.
+    pub fn stored_siblings(&self) -> &[Digest<H>] {
+        &self.siblings
+    }
+
+    fn is_set(&self, d: usize) -> bool {
+        self.bitmap[(d - 1) / 8] & (0x80 >> ((d - 1) % 8)) != 0
+    }
+}
+
+/// A node of a `SparseMerkleTree` held in memory, see `MemoryNodeStore`.
+///
+/// A subtree holding a single leaf is collapsed into one `Leaf` node, placed
+/// at the shallowest depth where its hashed key is distinguished from every
+/// other key, so the depth of the tree is O(log K) instead of 256.
+///
+/// A leaf keeps the original key next to its hashed key, so the contents of
+/// the tree can be listed, and a branch keeps the number of leaves below it.
+#[derive(Debug, Clone, Default)]
+pub enum Node<K = String, V = String, H: Hasher = Sha256> {
+    #[default]
+    Empty,
+    Leaf {
+        key: Digest<H>,
+        original_key: K,
+        value: V,
+        hash: Digest<H>,
+    },
+    Branch {
+        left: Arc<Node<K, V, H>>,
+        right: Arc<Node<K, V, H>>,
+        len: usize,
+        hash: Digest<H>,
+    },
+}
+
+/// An authenticated KV store where each `(K,V)` pair is a leaf of a binary
+/// tree, located by reading the bits of `hash_key(K)` as `0 -> left`,
+/// `1 -> right`.
+///
+/// The nodes of the tree are kept in a `NodeStore`. By default that is a
+/// `MemoryNodeStore`: cloning the tree is O(1), and `insert` or `remove`
+/// only allocate the nodes on the changed path, so old versions can be kept
+/// around cheaply. With any other store the tree is a
+/// `StoredSparseMerkleTree`, whose operations can fail with the errors of
+/// the store.
+///
+/// Keys and values can be any type with a canonical byte encoding, which is
+/// what gets hashed.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<
+    K = String,
+    V = String,
+    H: Hasher = Sha256,
+    S = MemoryNodeStore<K, V, H>,
+> where
+    K: Clone,
+    V: Clone,
+    S: NodeStore<K, V, H>,
+{
+    store: S,
+    root: S::Ref,
+    len: usize,
+}
+
+/// An iterator over the entries of an in-memory `SparseMerkleTree`, in the
+/// order of their hashed keys.
+#[derive(Debug, Clone)]
+pub struct Iter<'a, K, V, H: Hasher> {
+    // the subtrees left to visit, the next one on top
+    stack: Vec<&'a Node<K, V, H>>,
+    remaining: usize,
+}
+
+impl<'a, K, V, H: Hasher> Iterator for Iter<'a, K, V, H> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            match node {
+                Node::Empty => {}
+                Node::Leaf {
+                    original_key,
+                    value,
+                    ..
+                } => {
+                    self.remaining -= 1;
+                    return Some((original_key, value));
+                }
+                Node::Branch { left, right, .. } => {
+                    self.stack.push(right);
+                    self.stack.push(left);
+                }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V, H: Hasher> ExactSizeIterator for Iter<'a, K, V, H> {}
+
+/// An iterator over the entries of a `SparseMerkleTree` in any
+/// `NodeStore`, in the order of their hashed keys. It stops after the first
+/// node which can't be read.
+pub struct TryIter<'a, K, V, H, S>
+where
+    K: Clone,
+    V: Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H>,
+{
+    tree: &'a SparseMerkleTree<K, V, H, S>,
+    // the subtrees left to visit with their depths, the next one on top
+    stack: Vec<(S::Ref, usize)>,
+}
+
+impl<'a, K, V, H, S> Iterator for TryIter<'a, K, V, H, S>
+where
+    K: Clone,
+    V: Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H>,
+{
+    type Item = io::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth)) = self.stack.pop() {
+            match self.tree.store.get(&node, depth) {
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+                Ok(StoredNode::Empty) => {}
+                Ok(StoredNode::Leaf {
+                    original_key,
+                    value,
+                    ..
+                }) => {
+                    return Some(Ok((
+                        original_key.into_owned(),
+                        value.into_owned(),
+                    )));
+                }
+                Ok(StoredNode::Branch { left, right, .. }) => {
+                    self.stack.push((right, depth + 1));
+                    self.stack.push((left, depth + 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SparseMerkleTreeProof<H: Hasher = Sha256> {
+    /// The sibling hashes from the node where the key's path ends up to the
+    /// root. The path ends either in an empty subtree (`leaf == None`) or in
+    /// a leaf holding another key, given as (hashed key, hashed value).
+    NotPresent {
+        siblings: CompressedPath<H>,
+        leaf: Option<(Digest<H>, Digest<H>)>,
+    },
+    /// The sibling hashes from the leaf holding the key up to the root.
+    Present { siblings: CompressedPath<H> },
+}
+
+impl<H: Hasher> SparseMerkleTreeProof<H> {
+    /// The compressed sibling hashes of the path.
+    pub fn path(&self) -> &CompressedPath<H> {
+        match self {
+            SparseMerkleTreeProof::NotPresent { siblings, .. }
+            | SparseMerkleTreeProof::Present { siblings } => siblings,
+        }
+    }
+
+    /// The sibling hashes, ordered from the bottom of the path to the root.
+    pub fn siblings(&self) -> Vec<Digest<H>> {
+        self.path().decompress()
+    }
+
+    /// The depth of the node where the key's path ends.
+    pub fn depth(&self) -> usize {
+        self.path().depth()
+    }
+
+    pub fn is_present(&self) -> bool {
+        matches!(self, SparseMerkleTreeProof::Present { .. })
+    }
+
+    /// Check that looking up `key` in the tree committed to by `comm`
+    /// results in `res`. This is `SparseMerkleTree::check_proof`, usable
+    /// without a tree in scope.
+    //
+    // for membership the path starts from the leaf digest of (key, value),
+    // for non-membership it starts from the empty subtree digest, or from the leaf
+    // which occupies the key's path. That leaf must hold a different key whose hash
+    // shares the prefix of the path, otherwise the key would be stored there.
+    pub fn check<K: Encode + ?Sized, V: Encode + ?Sized>(
+        &self,
+        key: &K,
+        res: Option<&V>,
+        comm: &Digest<H>,
+    ) -> Option<()> {
+        let h_k_digest = smt_util::hash_key(key);
+        let h_k = KeyPath::from(h_k_digest);
+
+        let merkle_root = match (res, self) {
+            (None, SparseMerkleTreeProof::NotPresent { siblings, leaf }) => {
+                let depth = siblings.depth();
+                if depth > TREE_DEPTH {
+                    return None;
+                }
+
+                let node = match leaf {
+                    None => smt_util::empty_hash(TREE_DEPTH - depth),
+                    Some((other, h_v)) => {
+                        if *other == h_k_digest
+                            || !KeyPath::from(*other).starts_with(&h_k, depth)
+                        {
+                            return None;
+                        }
+
+                        smt_util::hash_leaf(*other, *h_v)
+                    }
+                };
+
+                smt_util::root_from_siblings(node, &h_k, siblings)?
+            }
+            (Some(val), SparseMerkleTreeProof::Present { siblings }) => {
+                smt_util::root_from_path(siblings, key, val)?
+            }
+            _ => return None,
+        };
+
+        if merkle_root != *comm {
+            return None;
+        }
+
+        Some(())
+    }
+}
+
+/// An update applied by `SparseMerkleTree::apply_batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp<K = String, V = String> {
+    Insert(K, V),
+    Remove(K),
+}
+
+// an update of one leaf: (path of the hashed key, hashed key, new entry or `None` to remove it)
+type Update<K, V, H> = (KeyPath, Digest<H>, Option<(K, V)>);
+
+// a key checked by a multiproof: (path of the hashed key, hashed key, hashed value or `None` if absent)
+type Lookup<H> = (KeyPath, Digest<H>, Option<Digest<H>>);
+
+// a leaf placed by `SparseMerkleTree::build_parallel`: (path of the hashed key, leaf)
+type PathLeaf<K, V, H> = (KeyPath, Node<K, V, H>);
+
+/// A node of a `SparseMerkleTreeMultiProof`, which lists the part of the
+/// tree covering the paths of every queried key in pre-order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultiProofNode<H: Hasher = Sha256> {
+    /// A subtree which no queried key's path enters, given by its digest.
+    Hash(Digest<H>),
+    /// An empty subtree where the paths of some queried keys end.
+    Empty,
+    /// A leaf where the paths of some queried keys end, given as
+    /// (hashed key, hashed value).
+    Leaf(Digest<H>, Digest<H>),
+    /// A branch on the path of some queried keys. Its left and then its
+    /// right subtree follow.
+    Branch,
+}
+
+/// One proof for a batch of lookups, where the siblings and internal nodes
+/// shared by several paths appear only once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseMerkleTreeMultiProof<H: Hasher = Sha256> {
+    nodes: Vec<MultiProofNode<H>>,
+}
+
+impl<H: Hasher> SparseMerkleTreeMultiProof<H> {
+    pub fn from_nodes(nodes: Vec<MultiProofNode<H>>) -> Self {
+        SparseMerkleTreeMultiProof { nodes }
+    }
+
+    pub fn nodes(&self) -> &[MultiProofNode<H>] {
+        &self.nodes
+    }
+
+    /// Check that looking up each of `keys` in the tree committed to by
+    /// `comm` results in the matching entry of `results`.
+    //
+    // the root is rebuilt from the nodes in pre-order, while the keys are
+    // routed down the branches by their direction bits. Every key must end
+    // in an empty subtree or a leaf, an opaque `Hash` can't hold any key.
+    pub fn check<K: Encode, V: Encode>(
+        &self,
+        keys: &[K],
+        results: &[Option<V>],
+        comm: &Digest<H>,
+    ) -> Option<()> {
+        if keys.len() != results.len() {
+            return None;
+        }
+
+        let mut lookups: Vec<_> = keys
+            .iter()
+            .zip(results.iter())
+            .map(|(k, res)| {
+                let h_k = smt_util::hash_key(k);
+                let h_v = res.as_ref().map(smt_util::hash_value);
+                (KeyPath::from(h_k), h_k, h_v)
+            })
+            .collect();
+        lookups.sort_by_key(|lookup| lookup.0);
+
+        let mut nodes = self.nodes.iter();
+        let empty = EmptyHashes::get();
+        let merkle_root = Self::check_node(&mut nodes, 0, &lookups, empty)?;
+
+        if nodes.next().is_some() || merkle_root != *comm {
+            return None;
+        }
+
+        Some(())
+    }
+
+    /// Check that this is a witness (from `SparseMerkleTree::prove_update`)
+    /// for applying `ops` in order to the tree committed to by `old_root`,
+    /// and return the commitment of the resulting tree.
+    ///
+    /// Returns `None` if the witness doesn't authenticate against
+    /// `old_root`, or doesn't cover every node the ops touch.
+    //
+    // the witness is rebuilt into a partial tree, and the ops are applied to it like
+    // SparseMerkleTree::apply_at does. Whenever an op would need the contents of an opaque
+    // subtree, either to follow a path into it or to decide if it moves up after a
+    // removal, the witness is rejected.
+    pub fn check_update<K: Encode, V: Encode>(
+        &self,
+        old_root: &Digest<H>,
+        ops: &[BatchOp<K, V>],
+    ) -> Option<Digest<H>> {
+        let mut nodes = self.nodes.iter();
+        let empty = EmptyHashes::get();
+        let mut tree =
+            PartialNode::parse(&mut nodes, KeyPath::default(), 0, empty)?;
+
+        if nodes.next().is_some() || tree.hash(0, empty) != *old_root {
+            return None;
+        }
+
+        for op in ops {
+            match op {
+                BatchOp::Insert(k, v) => {
+                    let key = smt_util::hash_key(k);
+                    let hash = smt_util::hash_kv(k, v);
+                    tree.insert(&KeyPath::from(key), 0, key, hash)?;
+                }
+                BatchOp::Remove(k) => {
+                    let key = smt_util::hash_key(k);
+                    tree.remove(&KeyPath::from(key), 0, &key)?;
+                }
+            }
+        }
+
+        Some(tree.hash(0, empty))
+    }
+
+    // check_node returns the digest of the subtree at `depth` whose nodes come next,
+    // if every lookup in `lookups` (sorted by hashed key) is consistent with it.
+    fn check_node<'a>(
+        nodes: &mut impl Iterator<Item = &'a MultiProofNode<H>>,
+        depth: usize,
+        lookups: &[Lookup<H>],
+        empty: EmptyHashes<H>,
+    ) -> Option<Digest<H>> {
+        match nodes.next()? {
+            MultiProofNode::Hash(hash) => {
+                if !lookups.is_empty() {
+                    return None;
+                }
+                Some(*hash)
+            }
+            MultiProofNode::Empty => {
+                if lookups.iter().any(|(_, _, h_v)| h_v.is_some()) {
+                    return None;
+                }
+                Some(empty.at(depth))
+            }
+            MultiProofNode::Leaf(other, other_h_v) => {
+                let other_h_k = KeyPath::from(*other);
+                for (h_k, h_k_digest, h_v) in lookups {
+                    let consistent = if h_k_digest == other {
+                        *h_v == Some(*other_h_v)
+                    } else {
+                        h_v.is_none() && other_h_k.starts_with(h_k, depth)
+                    };
+                    if !consistent {
+                        return None;
+                    }
+                }
+                Some(smt_util::hash_leaf(*other, *other_h_v))
+            }
+            MultiProofNode::Branch => {
+                if depth == TREE_DEPTH {
+                    return None;
+                }
+
+                let mid =
+                    lookups.partition_point(|(h_k, _, _)| !h_k.bit(depth));
+                let left =
+                    Self::check_node(nodes, depth + 1, &lookups[..mid], empty)?;
+                let right =
+                    Self::check_node(nodes, depth + 1, &lookups[mid..], empty)?;
+                Some(hash_branch(left, right))
+            }
+        }
+    }
+}
+
+// A part of a tree rebuilt from a `SparseMerkleTreeMultiProof`, where the subtrees
+// off the paths of the proof are opaque.
+enum PartialNode<H: Hasher> {
+    Opaque(Digest<H>),
+    Empty,
+    Leaf { key: Digest<H>, hash: Digest<H> },
+    Branch(Box<PartialNode<H>>, Box<PartialNode<H>>),
+}
+
+impl<H: Hasher> PartialNode<H> {
+    // parse rebuilds the subtree whose nodes come next, at the end of the first `depth` bits of `path`.
+    // A leaf's hashed key must start with those bits, since that's where the tree stores it.
+    //
+    // An empty subtree given as its digest, or as a branch of two empty subtrees, is rebuilt
+    // as `Empty`, so that remove sees it and moves a leaf next to it up.
+    fn parse<'a>(
+        nodes: &mut impl Iterator<Item = &'a MultiProofNode<H>>,
+        path: KeyPath,
+        depth: usize,
+        empty: EmptyHashes<H>,
+    ) -> Option<Self> {
+        Some(match nodes.next()? {
+            MultiProofNode::Hash(hash) => {
+                if *hash == empty.at(depth) {
+                    PartialNode::Empty
+                } else {
+                    PartialNode::Opaque(*hash)
+                }
+            }
+            MultiProofNode::Empty => PartialNode::Empty,
+            MultiProofNode::Leaf(key, h_v) => {
+                if !KeyPath::from(*key).starts_with(&path, depth) {
+                    return None;
+                }
+                PartialNode::Leaf {
+                    key: *key,
+                    hash: smt_util::hash_leaf(*key, *h_v),
+                }
+            }
+            MultiProofNode::Branch => {
+                if depth == TREE_DEPTH {
+                    return None;
+                }
+
+                let left = Self::parse(
+                    nodes,
+                    path.with_bit(depth, false),
+                    depth + 1,
+                    empty,
+                );
+                let right = Self::parse(
+                    nodes,
+                    path.with_bit(depth, true),
+                    depth + 1,
+                    empty,
+                );
+
+                match (left?, right?) {
+                    (PartialNode::Empty, PartialNode::Empty) => {
+                        PartialNode::Empty
+                    }
+                    (left, right) => {
+                        PartialNode::Branch(Box::new(left), Box::new(right))
+                    }
+                }
+            }
+        })
+    }
+
+    fn hash(&self, depth: usize, empty: EmptyHashes<H>) -> Digest<H> {
+        match self {
+            PartialNode::Opaque(hash) | PartialNode::Leaf { hash, .. } => *hash,
+            PartialNode::Empty => empty.at(depth),
+            PartialNode::Branch(left, right) => hash_branch(
+                left.hash(depth + 1, empty),
+                right.hash(depth + 1, empty),
+            ),
+        }
+    }
+
+    // insert follows SparseMerkleTree::apply_at for a lone insert, pushing a leaf with
+    // another key one level down until the paths part, and fails if the path enters
+    // an opaque subtree.
+    fn insert(
+        &mut self,
+        h_k: &KeyPath,
+        i: usize,
+        key: Digest<H>,
+        hash: Digest<H>,
+    ) -> Option<()> {
+        match self {
+            PartialNode::Opaque(_) => return None,
+            PartialNode::Leaf { key: other, .. } if *other != key => {
+                let other_bit = KeyPath::from(*other).bit(i);
+                let old = Box::new(std::mem::replace(self, PartialNode::Empty));
+                *self = if !other_bit {
+                    PartialNode::Branch(old, Box::new(PartialNode::Empty))
+                } else {
+                    PartialNode::Branch(Box::new(PartialNode::Empty), old)
+                };
+            }
+            _ => {}
+        }
+
+        match self {
+            PartialNode::Branch(left, right) => {
+                if !h_k.bit(i) {
+                    left.insert(h_k, i + 1, key, hash)
+                } else {
+                    right.insert(h_k, i + 1, key, hash)
+                }
+            }
+            _ => {
+                *self = PartialNode::Leaf { key, hash };
+                Some(())
+            }
+        }
+    }
+
+    // remove follows SparseMerkleTree::apply_at for a lone remove, moving a leaf left
+    // alone next to an empty subtree up. It fails if the path enters an opaque subtree
+    // or if an opaque subtree is left alone next to an empty one, since it would have
+    // to move up if it is a leaf.
+    fn remove(
+        &mut self,
+        h_k: &KeyPath,
+        i: usize,
+        key: &Digest<H>,
+    ) -> Option<()> {
+        match self {
+            PartialNode::Opaque(_) => return None,
+            PartialNode::Empty => {}
+            PartialNode::Leaf { key: other, .. } => {
+                if other == key {
+                    *self = PartialNode::Empty;
+                }
+            }
+            PartialNode::Branch(left, right) => {
+                if !h_k.bit(i) {
+                    left.remove(h_k, i + 1, key)?;
+                } else {
+                    right.remove(h_k, i + 1, key)?;
+                }
+
+                let leaf = match (left.as_mut(), right.as_mut()) {
+                    (PartialNode::Empty, PartialNode::Empty) => {
+                        PartialNode::Empty
+                    }
+                    (PartialNode::Empty, PartialNode::Opaque(_))
+                    | (PartialNode::Opaque(_), PartialNode::Empty) => {
+                        return None
+                    }
+                    (PartialNode::Empty, leaf @ PartialNode::Leaf { .. })
+                    | (leaf @ PartialNode::Leaf { .. }, PartialNode::Empty) => {
+                        std::mem::replace(leaf, PartialNode::Empty)
+                    }
+                    _ => return Some(()),
+                };
+                *self = leaf;
+            }
+        }
+
+        Some(())
+    }
+}
+
+impl<K, V, H, S> SparseMerkleTree<K, V, H, S>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H>,
+{
+    /// An empty tree keeping its nodes in `store`.
+    pub fn with_store(store: S) -> Self {
+        let root = store.empty(0);
+        SparseMerkleTree {
+            store,
+            root,
+            len: 0,
+        }
+    }
+
+    pub fn commit(&self) -> Digest<H> {
+        self.store.hash(&self.root, 0)
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub(crate) fn root_ref(&self) -> &S::Ref {
+        &self.root
+    }
+
+    // set_root points the tree at the subtree `root` of the store, holding `len` leaves.
+    pub(crate) fn set_root(&mut self, root: S::Ref, len: usize) {
+        self.root = root;
+        self.len = len;
+    }
+
+    /// Look up `key`, with a proof against `commit()`.
+    //
+    // get traverses the key's path and collects the merkle proof from the node where it ends.
+    // if it ends in the key's leaf the value is fetched from it,
+    // otherwise the empty subtree or the leaf of another key proves non-membership.
+    pub fn try_get(
+        &self,
+        key: &K,
+    ) -> io::Result<(Option<V>, SparseMerkleTreeProof<H>)> {
+        let h_k_digest = smt_util::hash_key(key);
+        let h_k = KeyPath::from(h_k_digest);
+
+        // siblings are pushed from the root down, and reversed at the end
+        let mut siblings = vec![];
+        let mut node = self.root.clone();
+        let end = loop {
+            let i = siblings.len();
+            // if the child is on left then sibling will be right one.
+            let (child, sib) = match self.store.get(&node, i)? {
+                StoredNode::Branch { left, right, .. } if !h_k.bit(i) => {
+                    (left, right)
+                }
+                StoredNode::Branch { left, right, .. } => (right, left),
+                end => break end,
+            };
+            siblings.push(self.store.hash(&sib, i + 1));
+            node = child;
+        };
+        siblings.reverse();
+        let siblings = CompressedPath::compress(&siblings);
+
+        Ok(match end {
+            StoredNode::Leaf { key, value, .. } if key == h_k_digest => (
+                Some(value.into_owned()),
+                SparseMerkleTreeProof::Present { siblings },
+            ),
+            StoredNode::Leaf { key, value, .. } => (
+                None,
+                SparseMerkleTreeProof::NotPresent {
+                    siblings,
+                    leaf: Some((key, smt_util::hash_value(value.as_ref()))),
+                },
+            ),
+            _ => (
+                None,
+                SparseMerkleTreeProof::NotPresent {
+                    siblings,
+                    leaf: None,
+                },
+            ),
+        })
+    }
+
+    /// Insert `key`, replacing its value if it is in the tree already.
+    pub fn try_insert(&mut self, key: K, value: V) -> io::Result<()> {
+        let h_k_digest = smt_util::hash_key(&key);
+        let h_k = KeyPath::from(h_k_digest);
+        self.apply(&mut [(h_k, h_k_digest, Some((key, value)))])
+    }
+
+    /// Remove `key`, if it is in the tree.
+    pub fn try_remove(&mut self, key: &K) -> io::Result<()> {
+        let h_k_digest = smt_util::hash_key(key);
+        let h_k = KeyPath::from(h_k_digest);
+        self.apply(&mut [(h_k, h_k_digest, None)])
+    }
+
+    /// Apply every op in `ops`, in order. The result is the same as
+    /// applying them one by one with `try_insert` and `try_remove`, but the
+    /// tree is descended once and each affected branch is written once.
+    /// The keys and values of the ops are moved into the tree.
+    pub fn try_apply_batch(
+        &mut self,
+        ops: Vec<BatchOp<K, V>>,
+    ) -> io::Result<()> {
+        // only the last op on each key matters
+        let mut last_ops = HashMap::new();
+        for op in ops {
+            let key = match &op {
+                BatchOp::Insert(k, _) | BatchOp::Remove(k) => k,
+            };
+            let h_k_digest = smt_util::hash_key(key);
+            last_ops.insert(KeyPath::from(h_k_digest), (h_k_digest, op));
+        }
+
+        let mut updates: Vec<Update<K, V, H>> = last_ops
+            .into_iter()
+            .map(|(h_k, (h_k_digest, op))| match op {
+                BatchOp::Insert(key, value) => {
+                    (h_k, h_k_digest, Some((key, value)))
+                }
+                BatchOp::Remove(_) => (h_k, h_k_digest, None),
+            })
+            .collect();
+        updates.sort_by_key(|update| update.0);
+
+        self.apply(&mut updates)
+    }
+
+    /// Look up every key in `keys`, with one proof for all of them.
+    pub fn try_get_many(
+        &self,
+        keys: &[K],
+    ) -> io::Result<(Vec<Option<V>>, SparseMerkleTreeMultiProof<H>)> {
+        let mut h_ks: Vec<KeyPath> = keys
+            .iter()
+            .map(|k| KeyPath::from(smt_util::hash_key::<K, H>(k)))
+            .collect();
+        let paths = h_ks.clone();
+        h_ks.sort();
+        h_ks.dedup();
+
+        let mut nodes = Vec::new();
+        let mut values = HashMap::new();
+        self.multiproof_at(
+            &self.root,
+            0,
+            &h_ks,
+            false,
+            &mut nodes,
+            &mut values,
+        )?;
+
+        let results =
+            paths.iter().map(|h_k| values.get(h_k).cloned()).collect();
+        Ok((results, SparseMerkleTreeMultiProof { nodes }))
+    }
+
+    /// Generate a witness that applying `ops` to this tree results in the
+    /// tree `try_apply_batch(ops)` leaves behind, see
+    /// `SparseMerkleTreeMultiProof::check_update`.
+    ///
+    /// The witness is a multiproof for every key in `ops`, where the
+    /// siblings of their paths are also opened one level.
+    pub fn try_prove_update(
+        &self,
+        ops: &[BatchOp<K, V>],
+    ) -> io::Result<SparseMerkleTreeMultiProof<H>> {
+        let mut h_ks: Vec<KeyPath> = ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Insert(k, _) | BatchOp::Remove(k) => {
+                    KeyPath::from(smt_util::hash_key::<K, H>(k))
+                }
+            })
+            .collect();
+        h_ks.sort();
+        h_ks.dedup();
+
+        let mut nodes = Vec::new();
+        let mut values = HashMap::new();
+        self.multiproof_at(
+            &self.root,
+            0,
+            &h_ks,
+            true,
+            &mut nodes,
+            &mut values,
+        )?;
+
+        Ok(SparseMerkleTreeMultiProof { nodes })
+    }
+
+    /// Every entry in the tree, in the order of their hashed keys.
+    pub fn try_iter(&self) -> TryIter<'_, K, V, H, S> {
+        TryIter {
+            tree: self,
+            stack: vec![(self.root.clone(), 0)],
+        }
+    }
+
+    // apply applies `updates` (sorted by hashed key, at most one per key) to the whole tree.
+    fn apply(&mut self, updates: &mut [Update<K, V, H>]) -> io::Result<()> {
+        let (root, added) = self.apply_at(self.root.clone(), 0, updates)?;
+        self.root = root;
+        self.len = (self.len as isize + added) as usize;
+        Ok(())
+    }
+
+    // apply_at applies `updates` (sorted by hashed key, at most one per key) to the subtree
+    // `node` at depth `i` in a single descent, writing every new node and returning the new
+    // subtree along with the number of leaves added to it.
+    //
+    // An insert reaching an empty subtree or a leaf with the same key puts its leaf there,
+    // and a remove reaching the leaf of its key empties it. Anything else reaching an empty
+    // subtree or a leaf splits it into a branch, pushing the leaf one level down along its
+    // own path, and the updates are partitioned between its children by direction bit, so
+    // branches are created only until the paths of the keys part. While returning back to
+    // root each branch on the way is written once, after all of its updates are applied,
+    // and collapsed if it is left with a single leaf. A subtree whose digest doesn't change
+    // is kept, so nothing is written for updates which change nothing.
+    // The new entries are taken out of `updates`.
+    fn apply_at(
+        &mut self,
+        node: S::Ref,
+        i: usize,
+        updates: &mut [Update<K, V, H>],
+    ) -> io::Result<(S::Ref, isize)> {
+        if updates.is_empty() {
+            return Ok((node, 0));
+        }
+
+        let (left, right, len) = match (self.shape(&node, i)?, &mut *updates) {
+            (StoredNode::Branch { left, right, len }, _) => (left, right, len),
+            (StoredNode::Empty, [(_, key, entry)]) => {
+                return match entry.take() {
+                    Some((original_key, value)) => {
+                        let leaf = StoredNode::Leaf {
+                            key: *key,
+                            original_key,
+                            value,
+                        };
+                        Ok((self.store.put(leaf, i)?, 1))
+                    }
+                    None => Ok((node, 0)),
+                };
+            }
+            (StoredNode::Leaf { key: other, .. }, [(_, key, entry)])
+                if other == *key =>
+            {
+                return match entry.take() {
+                    Some((original_key, value)) => {
+                        // an identical leaf is kept as it is
+                        let hash = smt_util::hash_leaf(
+                            *key,
+                            smt_util::hash_value(&value),
+                        );
+                        if hash == self.store.hash(&node, i) {
+                            return Ok((node, 0));
+                        }
+                        let leaf = StoredNode::Leaf {
+                            key: *key,
+                            original_key,
+                            value,
+                        };
+                        Ok((self.store.put(leaf, i)?, 0))
+                    }
+                    None => Ok((self.store.empty(i), -1)),
+                };
+            }
+            (StoredNode::Leaf { .. }, [(_, _, None)]) => return Ok((node, 0)),
+            // a leaf's digest doesn't depend on its depth, so it is pushed
+            // down by referencing it one level lower
+            (StoredNode::Leaf { key: other, .. }, _) => {
+                let empty = self.store.empty(i + 1);
+                if !KeyPath::from(other).bit(i) {
+                    (node.clone(), empty, 1)
+                } else {
+                    (empty, node.clone(), 1)
+                }
+            }
+            (StoredNode::Empty, _) => {
+                (self.store.empty(i + 1), self.store.empty(i + 1), 0)
+            }
+        };
+
+        let mid = updates.partition_point(|(h_k, _, _)| !h_k.bit(i));
+        let (to_left, to_right) = updates.split_at_mut(mid);
+        let (new_left, left_added) =
+            self.apply_at(left.clone(), i + 1, to_left)?;
+        let (new_right, right_added) =
+            self.apply_at(right.clone(), i + 1, to_right)?;
+
+        let unchanged = |store: &S, old: &S::Ref, new: &S::Ref| {
+            store.hash(old, i + 1) == store.hash(new, i + 1)
+        };
+        if unchanged(&self.store, &left, &new_left)
+            && unchanged(&self.store, &right, &new_right)
+        {
+            return Ok((node, 0));
+        }
+
+        let added = left_added + right_added;
+        let len = (len as isize + added) as usize;
+        Ok((self.branch(new_left, new_right, len, i)?, added))
     }
 
-    pub fn hash_kv(k: &str, v: &str) -> Digest {
-        hash_two_things("hash_kv_K", "hash_kv_V", k, v)
+    // shape reads the subtree `node` at depth `i`, leaving out the original key and
+    // value of a leaf, so the store can be written to while it is looked at.
+    fn shape(
+        &self,
+        node: &S::Ref,
+        i: usize,
+    ) -> io::Result<StoredNode<(), (), H, S::Ref>> {
+        Ok(match self.store.get(node, i)? {
+            StoredNode::Empty => StoredNode::Empty,
+            StoredNode::Leaf { key, .. } => StoredNode::Leaf {
+                key,
+                original_key: (),
+                value: (),
+            },
+            StoredNode::Branch { left, right, len } => {
+                StoredNode::Branch { left, right, len }
+            }
+        })
     }
 
-    pub fn hash_branch(l: Digest, r: Digest) -> Digest {
-        hash_two_things("hash_branch_L", "hash_branch_R", l, r)
+    // branch writes the branch at depth `i` with the children `left` and `right`, holding
+    // `len` leaves. A branch without leaves is an empty subtree, and a branch left with a
+    // single leaf and an empty sibling is replaced by the leaf, moved up from below.
+    fn branch(
+        &mut self,
+        left: S::Ref,
+        right: S::Ref,
+        len: usize,
+        i: usize,
+    ) -> io::Result<S::Ref> {
+        match len {
+            0 => Ok(self.store.empty(i)),
+            1 if self.store.is_empty(&left, i + 1) => Ok(right),
+            1 if self.store.is_empty(&right, i + 1) => Ok(left),
+            _ => self.store.put(StoredNode::Branch { left, right, len }, i),
+        }
     }
 
-    // root_from_path takes siblings along the path from leaf to merkle root
-    // it calculates the digest of the leaf and check's the branch node is left node
-    // or right node based on bitstring and then hashes it appropriately untill root node is calculated.
-    pub fn root_from_path(path: &[Digest], k: &str, v: &str) -> Digest {
-        let mut running_hash = hash_kv(k, v);
+    // multiproof_at pushes the nodes covering the paths of every key in `h_ks` in pre-order,
+    // and the values of the keys whose leaves it reaches into `values`.
+    // `h_ks` are sorted, so at each branch the keys going left come before the keys going right.
+    // Subtrees which none of the paths enter are pushed as their digest only, unless `reveal_siblings`
+    // is set: then the siblings of the paths are opened one level, so it is known whether they are
+    // an empty subtree, a leaf or a branch.
+    fn multiproof_at(
+        &self,
+        node: &S::Ref,
+        i: usize,
+        h_ks: &[KeyPath],
+        reveal_siblings: bool,
+        nodes: &mut Vec<MultiProofNode<H>>,
+        values: &mut HashMap<KeyPath, V>,
+    ) -> io::Result<()> {
+        if h_ks.is_empty() && !reveal_siblings {
+            nodes.push(MultiProofNode::Hash(self.store.hash(node, i)));
+            return Ok(());
+        }
 
-        let h_k: String =
-            smt_util::hash_key(k).string().chars().rev().collect();
+        match self.store.get(node, i)? {
+            StoredNode::Branch { left, right, .. } if h_ks.is_empty() => {
+                nodes.push(MultiProofNode::Branch);
+                nodes.push(MultiProofNode::Hash(self.store.hash(&left, i + 1)));
+                nodes
+                    .push(MultiProofNode::Hash(self.store.hash(&right, i + 1)));
+            }
+            StoredNode::Empty => nodes.push(MultiProofNode::Empty),
+            StoredNode::Leaf { key, value, .. } => {
+                let h_v = smt_util::hash_value(value.as_ref());
+                nodes.push(MultiProofNode::Leaf(key, h_v));
 
-        for (i, sib) in path.iter().enumerate() {
-            if h_k.chars().nth(i).unwrap() == '0' {
-                // if leaf is on left then sibling should be on the right
-                running_hash = hash_branch(running_hash, *sib)
-            } else {
-                running_hash = hash_branch(*sib, running_hash)
+                let h_k = KeyPath::from(key);
+                if h_ks.binary_search(&h_k).is_ok() {
+                    values.insert(h_k, value.into_owned());
+                }
+            }
+            StoredNode::Branch { left, right, .. } => {
+                nodes.push(MultiProofNode::Branch);
+
+                let mid = h_ks.partition_point(|h_k| !h_k.bit(i));
+                self.multiproof_at(
+                    &left,
+                    i + 1,
+                    &h_ks[..mid],
+                    reveal_siblings,
+                    nodes,
+                    values,
+                )?;
+                self.multiproof_at(
+                    &right,
+                    i + 1,
+                    &h_ks[mid..],
+                    reveal_siblings,
+                    nodes,
+                    values,
+                )?;
             }
         }
 
-        running_hash
+        Ok(())
     }
 }
-#[derive(Debug, Clone)]
-struct Node {
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
-    hash: Digest,
+
+// infallible unwraps the result of an operation on an in-memory tree, whose
+// MemoryNodeStore never fails.
+fn infallible<T>(res: io::Result<T>) -> T {
+    res.expect("MemoryNodeStore doesn't fail")
 }
 
-impl Default for Node {
-    fn default() -> Self {
-        Node {
-            left: None,
-            right: None,
-            hash: zero_digest(),
+impl<K, V, H> SparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    /// Check a proof returned by `get_many` or `try_get_many`, see
+    /// `SparseMerkleTreeMultiProof::check`.
+    pub fn check_multiproof(
+        keys: &[K],
+        results: &[Option<V>],
+        proof: &SparseMerkleTreeMultiProof<H>,
+        root: &Digest<H>,
+    ) -> Option<()> {
+        proof.check(keys, results, root)
+    }
+
+    pub fn root(&self) -> &Node<K, V, H> {
+        &self.root
+    }
+
+    /// Every entry in the tree, in the order of their hashed keys.
+    pub fn iter(&self) -> Iter<'_, K, V, H> {
+        Iter {
+            stack: vec![&self.root],
+            remaining: self.len,
         }
     }
-}
 
-#[derive(Debug, Clone)]
-struct SparseMerkleTree {
-    root: Node,
-    store: HashMap<String, String>,
-}
+    pub fn keys(&self) -> impl Iterator<Item = &K> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
 
-#[derive(Debug, Clone)]
-enum SparseMerkleTreeProof {
-    NotPresent,
-    Present { siblings: Vec<Digest> },
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.iter().map(|(_, v)| v)
+    }
+
+    /// Look up every key in `keys`, with one proof for all of them.
+    pub fn get_many(
+        &self,
+        keys: &[K],
+    ) -> (Vec<Option<V>>, SparseMerkleTreeMultiProof<H>) {
+        infallible(self.try_get_many(keys))
+    }
+
+    /// Apply every op in `ops`, in order, see `try_apply_batch`.
+    pub fn apply_batch(mut self, ops: Vec<BatchOp<K, V>>) -> Self {
+        infallible(self.try_apply_batch(ops));
+        self
+    }
+
+    /// Generate a witness that applying `ops` to this tree results in
+    /// `self.apply_batch(ops).commit()`, see `try_prove_update`.
+    pub fn prove_update(
+        &self,
+        ops: &[BatchOp<K, V>],
+    ) -> SparseMerkleTreeMultiProof<H> {
+        infallible(self.try_prove_update(ops))
+    }
 }
 
-impl Node {
-    // get_proof takes hashed key as string and traverses untill leaf node is reached based on the direction bit
-    // after returning from leaf it pushes the sibling of leaf into siblings vector
-    // so siblings are captured from leaf on the path to the root
-    fn get_proof(&self, h_k: &String, i: u32, siblings: &mut Vec<Digest>) {
-        if self.left.is_none() && self.right.is_none() {
-            return;
+impl<K, V, H> SparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone + Send + Sync,
+    V: Encode + Clone + Send + Sync,
+    H: Hasher,
+{
+    /// Build the tree holding `entries` on `threads` threads, or on 256
+    /// threads if more are asked for. The result is the same as inserting
+    /// the entries one by one, so a key given more than once ends up with
+    /// its last value.
+    //
+    // the entries are hashed in parallel, one chunk per thread, and put into
+    // buckets by the first `prefix_len` bits of their hashed keys. Each bucket
+    // is built on its own thread into the subtree at the end of its prefix,
+    // then the subtrees are joined into the levels above them.
+    pub fn build_parallel(
+        entries: impl IntoIterator<Item = (K, V)>,
+        threads: usize,
+    ) -> Self {
+        let threads = threads.clamp(1, MAX_BUILD_THREADS);
+        let empty = EmptyHashes::get();
+        let prefix_len = threads.next_power_of_two().trailing_zeros() as usize;
+
+        // split the entries into owned chunks, taken off the end
+        let mut entries: Vec<(K, V)> = entries.into_iter().collect();
+        let chunk_len = entries.len().div_ceil(threads).max(1);
+        let mut chunks = vec![];
+        while !entries.is_empty() {
+            let at = entries.len().saturating_sub(chunk_len);
+            chunks.push(entries.split_off(at));
         }
+        chunks.reverse();
 
-        // if the child is on left then sibling will be right one, so store right sibling's hash at ith index.
-        if h_k.chars().nth((i) as usize).unwrap() == '0' {
-            self.left.as_ref().unwrap().get_proof(h_k, i + 1, siblings);
-            siblings.push(self.right.as_ref().unwrap().hash);
-        } else {
-            self.right.as_ref().unwrap().get_proof(h_k, i + 1, siblings);
-            siblings.push(self.left.as_ref().unwrap().hash);
-        }
-    }
-
-    // insert_leaf traverses till pre-leaf based on direction bit,
-    // if either of left or right node doesn't exist on the path then a default node with zero digest is created
-    // as they are needed to calculate merkle root.
-    // Once we reach pre-leaf node, based on the direction bit
-    // We create leaf node using kv digest and default sibling node to preleaf if it isn't not present.
-    // While returning back to root, hash of nodes on path are re-calculated.
-    fn insert_leaf(&mut self, h_k: &String, i: u32, h_kv: &Digest) {
-        if i == 255 {
-            if h_k.chars().nth((i) as usize).unwrap() == '0' {
-                self.left = Some(Box::new(Node {
-                    left: None,
-                    right: None,
-                    hash: *h_kv,
-                }));
-
-                self.right.get_or_insert_with(Box::default);
-            } else {
-                self.right = Some(Box::new(Node {
-                    left: None,
-                    right: None,
-                    hash: *h_kv,
-                }));
+        let hashed: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| s.spawn(move || Self::bucket(chunk, prefix_len)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
 
-                self.left.get_or_insert_with(Box::default);
+        // gather each bucket from every chunk, keeping the order of entries
+        let mut buckets: Vec<Vec<_>> =
+            (0..1 << prefix_len).map(|_| vec![]).collect();
+        for chunk in hashed {
+            for (bucket, leaves) in buckets.iter_mut().zip(chunk) {
+                bucket.extend(leaves);
             }
+        }
 
-            self.hash = hash_branch(
-                self.left.as_ref().unwrap().hash,
-                self.right.as_ref().unwrap().hash,
-            );
+        let mut subtrees: Vec<(Node<K, V, H>, usize)> = thread::scope(|s| {
+            let handles: Vec<_> = buckets
+                .into_iter()
+                .map(|mut leaves| {
+                    s.spawn(move || {
+                        // the last entry of a key comes first after a stable
+                        // sort of the reversed entries, and is the one kept
+                        leaves.reverse();
+                        leaves.sort_by_key(|(h_k, _)| *h_k);
+                        leaves.dedup_by_key(|(h_k, _)| *h_k);
+                        let node =
+                            Node::build_sorted(&mut leaves, prefix_len, empty);
+                        (node, leaves.len())
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
 
-            return;
-        }
+        let len = subtrees.iter().map(|(_, len)| len).sum();
+        let mut roots: Vec<_> = subtrees
+            .iter_mut()
+            .map(|(node, _)| std::mem::take(node))
+            .collect();
+
+        let root = Node::join_sorted(&mut roots, 0, empty);
+        let mut tree = Self::new();
+        tree.set_root(Arc::new(root), len);
+        tree
+    }
 
-        self.left.get_or_insert_with(Box::default);
-        self.right.get_or_insert_with(Box::default);
+    // bucket turns `entries` into leaves, put into one bucket per value of the
+    // first `prefix_len` bits of their hashed keys.
+    fn bucket(
+        entries: Vec<(K, V)>,
+        prefix_len: usize,
+    ) -> Vec<Vec<PathLeaf<K, V, H>>> {
+        let mut buckets: Vec<Vec<_>> =
+            (0..1 << prefix_len).map(|_| vec![]).collect();
+        for (key, value) in entries {
+            let h_k_digest = smt_util::hash_key(&key);
+            let h_k = KeyPath::from(h_k_digest);
+            let leaf = Node::Leaf {
+                key: h_k_digest,
+                hash: smt_util::hash_kv(&key, &value),
+                original_key: key,
+                value,
+            };
 
-        if h_k.chars().nth((i) as usize).unwrap() == '0' {
-            self.left.as_mut().unwrap().insert_leaf(h_k, i + 1, h_kv);
-        } else {
-            self.right.as_mut().unwrap().insert_leaf(h_k, i + 1, h_kv);
+            let bucket = (0..prefix_len)
+                .fold(0, |bucket, i| bucket << 1 | h_k.bit(i) as usize);
+            buckets[bucket].push((h_k, leaf));
         }
+        buckets
+    }
+}
 
-        self.hash = smt_util::hash_branch(
-            self.left.as_ref().unwrap().hash,
-            self.right.as_ref().unwrap().hash,
-        )
+impl<K, V, H> Default for SparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+impl<'a, K, V, H> IntoIterator for &'a SparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, H>;
 
-    // remove_leaf traverses till leaf based on direction bit,
-    // then leaf hash is set to zero digest.
-    // while coming back we check if left and child right child's has zero digest
-    // then we remove those nodes by deallocating memory.
-    // we set the node's hash to zero digest after removing childs.
-    // Finally we calculate the hash when on of the childs has leaf
-    fn remove_leaf(&mut self, h_k: &String, i: u32) {
-        if self.left.is_none() && self.right.is_none() {
-            self.hash = zero_digest();
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
 
-            return;
+impl<K, V, H: Hasher> Node<K, V, H> {
+    /// The number of leaves in this subtree.
+    pub fn len(&self) -> usize {
+        match self {
+            Node::Empty => 0,
+            Node::Leaf { .. } => 1,
+            Node::Branch { len, .. } => *len,
         }
+    }
 
-        if h_k.chars().nth((i) as usize).unwrap() == '0' {
-            self.left.as_mut().unwrap().remove_leaf(h_k, i + 1);
-        } else {
-            self.right.as_mut().unwrap().remove_leaf(h_k, i + 1);
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Node::Empty)
+    }
+}
+
+impl<K: Clone, V: Encode + Clone, H: Hasher> Node<K, V, H> {
+    /// The digest of this node when it is rooted at `depth` below the
+    /// root. Only empty subtrees depend on their depth.
+    pub fn hash(&self, depth: usize) -> Digest<H> {
+        self.hash_with(depth, EmptyHashes::get())
+    }
+
+    // hash_with is `hash`, taking the digests of empty subtrees from `empty`.
+    pub(crate) fn hash_with(
+        &self,
+        depth: usize,
+        empty: EmptyHashes<H>,
+    ) -> Digest<H> {
+        match self {
+            Node::Empty => empty.at(depth),
+            Node::Leaf { hash, .. } | Node::Branch { hash, .. } => *hash,
         }
+    }
 
-        if self.left.as_ref().unwrap().hash == zero_digest()
-            && self.right.as_ref().unwrap().hash == zero_digest()
-        {
-            self.left.take();
-            self.right.take();
-            self.hash = zero_digest();
+    // build_sorted builds the subtree at depth `i` holding the leaves `leaves`, which
+    // are sorted by path and have distinct keys. The leaves are taken out of the slice.
+    fn build_sorted(
+        leaves: &mut [PathLeaf<K, V, H>],
+        i: usize,
+        empty: EmptyHashes<H>,
+    ) -> Self {
+        match leaves {
+            [] => Node::Empty,
+            [(_, leaf)] => std::mem::take(leaf),
+            _ => {
+                let mid = leaves.partition_point(|(h_k, _)| !h_k.bit(i));
+                let (left, right) = leaves.split_at_mut(mid);
+                Node::join(
+                    Node::build_sorted(left, i + 1, empty),
+                    Node::build_sorted(right, i + 1, empty),
+                    i,
+                    empty,
+                )
+            }
+        }
+    }
 
-            return;
+    // join_sorted joins the subtrees `nodes`, which are the subtrees at depth
+    // `i + log2(nodes.len())` from left to right, into the subtree at depth `i`.
+    fn join_sorted(
+        nodes: &mut [Node<K, V, H>],
+        i: usize,
+        empty: EmptyHashes<H>,
+    ) -> Self {
+        if let [node] = nodes {
+            return std::mem::take(node);
         }
 
-        self.hash = smt_util::hash_branch(
-            self.left.as_ref().unwrap().hash,
-            self.right.as_ref().unwrap().hash,
+        let (left, right) = nodes.split_at_mut(nodes.len() / 2);
+        Node::join(
+            Node::join_sorted(left, i + 1, empty),
+            Node::join_sorted(right, i + 1, empty),
+            i,
+            empty,
         )
     }
+
+    // join makes the branch at depth `i` with the children `left` and `right`.
+    // Like SparseMerkleTree::branch, a branch without leaves is an empty subtree
+    // and a branch holding a single leaf is replaced by the leaf.
+    fn join(left: Self, right: Self, i: usize, empty: EmptyHashes<H>) -> Self {
+        match (left, right) {
+            (Node::Empty, Node::Empty) => Node::Empty,
+            (Node::Empty, leaf @ Node::Leaf { .. })
+            | (leaf @ Node::Leaf { .. }, Node::Empty) => leaf,
+            (left, right) => Node::Branch {
+                hash: hash_branch(
+                    left.hash_with(i + 1, empty),
+                    right.hash_with(i + 1, empty),
+                ),
+                len: left.len() + right.len(),
+                left: Arc::new(left),
+                right: Arc::new(right),
+            },
+        }
+    }
 }
 
-impl AuthenticatedKV for SparseMerkleTree {
-    type K = String;
-    type V = String;
-    type LookupProof = SparseMerkleTreeProof;
-    type Commitment = Digest;
+impl<K, V, H> AuthenticatedKV for SparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    type K = K;
+    type V = V;
+    type LookupProof = SparseMerkleTreeProof<H>;
+    type Commitment = Digest<H>;
 
     /*
      *  *******************************************
@@ -189,19 +1640,12 @@ impl AuthenticatedKV for SparseMerkleTree {
      *  *******************************************
      */
     fn new() -> Self {
-        SparseMerkleTree {
-            root: Node {
-                left: None,
-                right: None,
-                hash: zero_digest(),
-            },
-            store: HashMap::new(),
-        }
+        Self::with_store(MemoryNodeStore::new())
     }
 
     // commit returns root node hash as root is calculated in insert function
     fn commit(&self) -> Self::Commitment {
-        self.root.hash
+        self.root.hash(0)
     }
 
     // check_proof checks if merkle root calculated from merkle proof matches the provided commitment
@@ -211,38 +1655,12 @@ impl AuthenticatedKV for SparseMerkleTree {
         pf: &Self::LookupProof,
         comm: &Self::Commitment,
     ) -> Option<()> {
-        match (res, pf) {
-            (None, SparseMerkleTreeProof::NotPresent) => {}
-            (Some(val), SparseMerkleTreeProof::Present { siblings }) => {
-                let merkle_root =
-                    smt_util::root_from_path(siblings, &key, &val);
-                if merkle_root != *comm {
-                    return None;
-                }
-            }
-            _ => return None,
-        }
-
-        Some(())
+        pf.check(&key, res.as_ref(), comm)
     }
 
-    // get checks if key is present in store, So non-membership of key is proved in O(1) time
-    // if key is present value is fetch from store and merkle proof is caculated.
+    // get looks the key up with try_get, see there.
     fn get(&self, key: Self::K) -> (Option<Self::V>, Self::LookupProof) {
-        let h_k: String = smt_util::hash_key(&key).string();
-        // let h_kv: String = smt_util::hash_kv(&key).string();
-
-        if let Some(val) = self.store.get(&h_k) {
-            let mut sib = Vec::new();
-            self.root.get_proof(&h_k, 0, &mut sib);
-
-            return (
-                Some(val.clone()),
-                SparseMerkleTreeProof::Present { siblings: sib },
-            );
-        }
-
-        (None, SparseMerkleTreeProof::NotPresent)
+        infallible(self.try_get(&key))
     }
 
     /*
@@ -250,65 +1668,41 @@ impl AuthenticatedKV for SparseMerkleTree {
      *                  TASK 6
      *  *******************************************
      *
-     * insert doesn't insert if both key and value are already present in store
-     * if only key exists but value is different then the value is replaced and merkle root is calculated
-     * if key doesn't exist the kv pair is inserted in store and merkle root is calculated
+     * insert doesn't insert if both key and value are already present in the tree
+     * if only key exists but value is different then the leaf is replaced and merkle root is calculated
+     * if key doesn't exist the kv pair is inserted as a new leaf and merkle root is calculated
      */
-    fn insert(self, key: Self::K, value: Self::V) -> Self {
-        let mut store = self.store;
-        let mut node = self.root;
-
-        let h_k = smt_util::hash_key(&key).string();
-        let h_kv = smt_util::hash_kv(&key, &value);
-
-        // if k,v is duplicate the donot insert it.
-        if let Some(val) = store.get(&h_k) {
-            if *val == value {
-                return SparseMerkleTree { root: node, store };
-            }
-        }
-
-        store.insert(h_k.clone(), value.clone());
-        node.insert_leaf(&h_k, 0, &h_kv);
-
-        SparseMerkleTree { root: node, store }
+    fn insert(mut self, key: Self::K, value: Self::V) -> Self {
+        infallible(self.try_insert(key, value));
+        self
     }
 
     /*
      *  *******************************************
      *                  TASK 6
      *  *******************************************
-     * remove checks if key present in store then it will be removed and merkle root is updated
-     * otherwise we don't get into merkle tree
+     * remove checks if key present in the tree then it will be removed and merkle root is updated
+     * otherwise the tree is left as it is
      *
      */
-    fn remove(self, key: Self::K) -> Self {
-        let mut node = self.root;
-        let mut store = self.store;
-
-        let h_k = smt_util::hash_key(&key).string();
-
-        // if key not found in store as we don't need to update merkle root
-        if store.remove(&h_k.clone()).is_none() {
-            return SparseMerkleTree { root: node, store };
-        }
-
-        node.remove_leaf(&h_k, 0);
-
-        SparseMerkleTree { root: node, store }
+    fn remove(mut self, key: Self::K) -> Self {
+        infallible(self.try_remove(&key));
+        self
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::sorted_kv::tests::InsertGetRemoveOp;
+    use crate::sorted_kv::tests::{
+        binary_entries, check_binary_keys_and_values, InsertGetRemoveOp,
+    };
     use quickcheck::quickcheck;
     use std::collections::HashMap;
 
     fn hash_smt_insert_get_remove(ops: Vec<InsertGetRemoveOp>) {
         let mut hmap = HashMap::new();
-        let mut smt = SparseMerkleTree::new();
+        let mut smt = <SparseMerkleTree>::new();
 
         for op in ops {
             match op {
@@ -318,7 +1712,7 @@ mod tests {
                 }
                 InsertGetRemoveOp::Get(k) => {
                     let (val, proof) = smt.get(k.clone());
-                    SparseMerkleTree::check_proof(
+                    <SparseMerkleTree>::check_proof(
                         k.clone(),
                         val.clone(),
                         &proof,
@@ -333,6 +1727,17 @@ mod tests {
                 }
             }
         }
+
+        // the tree lists the same entries, in the order of their hashed keys
+        let mut entries: Vec<_> = hmap.iter().collect();
+        entries.sort_by_key(|(k, _)| {
+            KeyPath::from(smt_util::hash_key::<_, Sha256>(*k))
+        });
+        assert_eq!(smt.len(), entries.len());
+        assert_eq!(smt.iter().len(), entries.len());
+        assert_eq!(smt.iter().collect::<Vec<_>>(), entries);
+        assert!(smt.keys().eq(entries.iter().map(|(k, _)| *k)));
+        assert!(smt.values().eq(entries.iter().map(|(_, v)| *v)));
     }
 
     #[quickcheck]
@@ -340,6 +1745,37 @@ mod tests {
         hash_smt_insert_get_remove(ops);
     }
 
+    // KeyPath agrees with the bitstring of the digest
+    #[quickcheck]
+    fn key_path_matches_bitstring(a: String, b: String, i: u8) {
+        let a_digest = smt_util::hash_key::<_, Sha256>(&a);
+        let b_digest = smt_util::hash_key::<_, Sha256>(&b);
+        let (a_path, b_path) =
+            (KeyPath::from(a_digest), KeyPath::from(b_digest));
+        let (a_bits, b_bits) = (a_digest.string(), b_digest.string());
+
+        let i = i as usize;
+        assert_eq!(a_path.bit(i), &a_bits[i..=i] == "1");
+        assert_eq!(a_path.cmp(&b_path), a_bits.cmp(&b_bits));
+
+        let common = a_bits
+            .chars()
+            .zip(b_bits.chars())
+            .take_while(|(x, y)| x == y)
+            .count();
+        assert_eq!(a_path.common_prefix_len(&b_path), common);
+        assert_eq!(a_path.common_prefix_len(&a_path), TREE_DEPTH);
+        assert!(a_path.starts_with(&b_path, common));
+        assert_eq!(
+            a_path.starts_with(&b_path, common + 1),
+            common == TREE_DEPTH
+        );
+
+        let flipped = a_path.with_bit(i, !a_path.bit(i));
+        assert_eq!(a_path.common_prefix_len(&flipped), i);
+        assert_eq!(flipped.with_bit(i, a_path.bit(i)), a_path);
+    }
+
     #[test]
     fn hash_smt_insert_get_test_cases() {
         use InsertGetRemoveOp::*;
@@ -415,4 +1851,677 @@ mod tests {
             hash_smt_insert_get_remove(ops)
         }
     }
+
+    #[test]
+    fn hash_smt_reject_forged_non_membership() {
+        let smt = <SparseMerkleTree>::new()
+            .insert("80".to_string(), "".to_string())
+            .insert("9".to_string(), "".to_string());
+
+        // an empty proof only authenticates the empty tree
+        let empty = SparseMerkleTreeProof::NotPresent {
+            siblings: CompressedPath::compress(&[]),
+            leaf: None,
+        };
+        assert!(<SparseMerkleTree>::check_proof(
+            "80".to_string(),
+            None,
+            &empty,
+            &<SparseMerkleTree>::new().commit(),
+        )
+        .is_some());
+        assert!(<SparseMerkleTree>::check_proof(
+            "80".to_string(),
+            None,
+            &empty,
+            &smt.commit(),
+        )
+        .is_none());
+
+        // an exclusion proof for one key can't deny another key
+        let (val, proof) = smt.get("0".to_string());
+        assert_eq!(val, None);
+        <SparseMerkleTree>::check_proof(
+            "0".to_string(),
+            None,
+            &proof,
+            &smt.commit(),
+        )
+        .unwrap();
+        assert!(<SparseMerkleTree>::check_proof(
+            "80".to_string(),
+            None,
+            &proof,
+            &smt.commit(),
+        )
+        .is_none());
+
+        // a membership path can't be reused to deny the key
+        let (_, proof) = smt.get("9".to_string());
+        let siblings = match proof {
+            SparseMerkleTreeProof::Present { siblings } => siblings,
+            _ => panic!("expected a membership proof"),
+        };
+        assert!(<SparseMerkleTree>::check_proof(
+            "9".to_string(),
+            None,
+            &SparseMerkleTreeProof::NotPresent {
+                siblings,
+                leaf: None,
+            },
+            &smt.commit(),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn hash_smt_reject_forged_leaf_non_membership() {
+        let smt = <SparseMerkleTree>::new()
+            .insert("80".to_string(), "".to_string())
+            .insert("9".to_string(), "".to_string());
+
+        // opening the key's own leaf can't prove that it is absent
+        let (_, proof) = smt.get("9".to_string());
+        let siblings = match proof {
+            SparseMerkleTreeProof::Present { siblings } => siblings,
+            _ => panic!("expected a membership proof"),
+        };
+        let leaf = Some((smt_util::hash_key("9"), smt_util::hash_value("")));
+        assert!(<SparseMerkleTree>::check_proof(
+            "9".to_string(),
+            None,
+            &SparseMerkleTreeProof::NotPresent { siblings, leaf },
+            &smt.commit(),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn hash_smt_shortcut_leaves() {
+        // a single leaf is collapsed into the root
+        let smt =
+            <SparseMerkleTree>::new().insert("0".to_string(), "".to_string());
+        assert_eq!(smt.commit(), smt_util::hash_kv("0", ""));
+
+        let smt = smt.insert("1".to_string(), "".to_string());
+        let (_, proof) = smt.get("1".to_string());
+        match proof {
+            SparseMerkleTreeProof::Present { siblings } => {
+                assert!(siblings.depth() < TREE_DEPTH)
+            }
+            _ => panic!("expected a membership proof"),
+        }
+
+        // removing the sibling moves the leaf back up to the root
+        let smt = smt.remove("1".to_string());
+        assert_eq!(smt.commit(), smt_util::hash_kv("0", ""));
+    }
+
+    // the shape of the tree only depends on the keys it holds, so the root
+    // after any sequence of operations matches a tree built from the final map.
+    #[quickcheck]
+    fn hash_smt_history_independent(ops: Vec<InsertGetRemoveOp>) {
+        let mut hmap = HashMap::new();
+        let mut smt = <SparseMerkleTree>::new();
+
+        for op in ops {
+            match op {
+                InsertGetRemoveOp::Insert(k, v) => {
+                    hmap.insert(k.clone(), v.clone());
+                    smt = smt.insert(k, v);
+                }
+                InsertGetRemoveOp::Get(_) => {}
+                InsertGetRemoveOp::Remove(k) => {
+                    hmap.remove(&k);
+                    smt = smt.remove(k);
+                }
+            }
+        }
+
+        let rebuilt = hmap
+            .into_iter()
+            .fold(SparseMerkleTree::<_, _>::new(), |smt, (k, v)| {
+                smt.insert(k, v)
+            });
+        assert_eq!(smt.commit(), rebuilt.commit());
+    }
+
+    #[test]
+    fn hash_smt_proof_check_without_tree() {
+        let smt = <SparseMerkleTree>::default()
+            .insert("80".to_string(), "a".to_string())
+            .insert("9".to_string(), "b".to_string());
+        let comm = smt.commit();
+
+        let (val, proof) = smt.get("9".to_string());
+        drop(smt);
+
+        assert!(proof.is_present());
+        assert_eq!(proof.depth(), proof.siblings().len());
+        assert!(proof.depth() > 0);
+        proof.check("9", val.as_deref(), &comm).unwrap();
+        assert!(proof.check("9", Some("a"), &comm).is_none());
+        assert!(proof.check("9", None::<&str>, &comm).is_none());
+    }
+
+    #[test]
+    fn hash_smt_binary_keys_and_values() {
+        check_binary_keys_and_values(SparseMerkleTree::<_, _>::new());
+
+        // a proof checks against borrowed keys and values as well
+        let smt = binary_entries()
+            .into_iter()
+            .fold(SparseMerkleTree::<_, _>::new(), |smt, (k, v)| {
+                smt.insert(k, v)
+            });
+        let (val, proof) = smt.get(vec![0xfd]);
+        assert_eq!(val, None);
+        proof
+            .check(&[0xfd][..], None::<&[u8]>, &smt.commit())
+            .unwrap();
+
+        // fixed-size keys are encoded as big-endian bytes
+        let smt =
+            SparseMerkleTree::<_, _>::new().insert(7u64, "seven".to_string());
+        let (val, proof) = smt.get(7);
+        proof
+            .check(&[0, 0, 0, 0, 0, 0, 0, 7], val.as_deref(), &smt.commit())
+            .unwrap();
+    }
+
+    #[test]
+    fn hash_smt_other_hasher() {
+        let smt = (0..50).fold(
+            SparseMerkleTree::<String, String, Sha512_256>::new(),
+            |smt, i| smt.insert(i.to_string(), i.to_string()),
+        );
+        let comm = smt.commit();
+
+        for k in ["0", "49", "50"] {
+            let (val, proof) = smt.get(k.to_string());
+            proof.check(k, val.as_deref(), &comm).unwrap();
+        }
+
+        // the same entries commit to unrelated digests under SHA-256
+        let sha256 = (0..50).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+        assert_ne!(sha256.commit().as_ref(), comm.as_ref());
+        assert_ne!(
+            empty_hash::<Sha256>(0).as_ref(),
+            empty_hash::<Sha512_256>(0).as_ref()
+        );
+    }
+
+    // a hasher defined outside the crate only names its engine
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    struct OtherSha256;
+
+    impl Hasher for OtherSha256 {
+        type Engine = sha2::Sha256;
+        const NAME: &'static str = "other_sha256";
+    }
+
+    #[test]
+    fn hash_smt_third_party_hasher() {
+        let smt = (0..10).fold(
+            SparseMerkleTree::<String, String, OtherSha256>::new(),
+            |smt, i| smt.insert(i.to_string(), i.to_string()),
+        );
+        let sha256 = (0..10).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+        assert_eq!(smt.commit().as_ref(), sha256.commit().as_ref());
+        assert_eq!(
+            empty_hash::<OtherSha256>(TREE_DEPTH).as_ref(),
+            empty_hash::<Sha256>(TREE_DEPTH).as_ref()
+        );
+    }
+
+    #[test]
+    fn hash_smt_empty_hashes() {
+        for h in 1..=TREE_DEPTH {
+            assert_eq!(
+                empty_hash::<Sha256>(h),
+                smt_util::hash_branch(empty_hash(h - 1), empty_hash(h - 1))
+            );
+        }
+        assert_ne!(empty_hash::<Sha256>(0), zero_digest());
+
+        let smt = <SparseMerkleTree>::new();
+        assert_eq!(smt.commit(), empty_hash(TREE_DEPTH));
+
+        // removing the last key restores the empty root
+        let smt = smt
+            .insert("0".to_string(), "".to_string())
+            .insert("1".to_string(), "".to_string())
+            .remove("0".to_string())
+            .remove("1".to_string());
+        assert_eq!(smt.commit(), empty_hash(TREE_DEPTH));
+    }
+
+    #[test]
+    fn hash_smt_compressed_proofs() {
+        let smt = (0..2000).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), "".to_string())
+        });
+
+        let (val, proof) = smt.get("1000".to_string());
+        proof.check("1000", val.as_deref(), &smt.commit()).unwrap();
+
+        // only the non-default siblings are stored
+        let path = proof.path();
+        let siblings = proof.siblings();
+        assert_eq!(siblings.len(), path.depth());
+        assert!(path.stored_siblings().len() <= path.depth());
+        assert!(path.stored_siblings().len() < 32);
+        assert_eq!(CompressedPath::compress(&siblings), *path);
+
+        // a stored sibling must be marked in the bitmap
+        let mut extra = path.clone();
+        extra.siblings.push(zero_digest());
+        assert!(SparseMerkleTreeProof::Present { siblings: extra }
+            .check("1000", val.as_deref(), &smt.commit())
+            .is_none());
+
+        // a default sibling can't be stored explicitly
+        let h_k = KeyPath::from(smt_util::hash_key::<_, Sha256>("0"));
+        let node: Digest = smt_util::hash_kv("0", "");
+        let implicit = CompressedPath::compress(&[empty_hash(TREE_DEPTH - 1)]);
+        assert!(implicit.stored_siblings().is_empty());
+        assert!(smt_util::root_from_siblings(node, &h_k, &implicit).is_some());
+
+        let mut explicit = implicit;
+        explicit.bitmap[0] = 0x80;
+        explicit.siblings.push(empty_hash(TREE_DEPTH - 1));
+        assert!(smt_util::root_from_siblings(node, &h_k, &explicit).is_none());
+
+        // levels below the bottom of the path can't be marked
+        let mut deep = CompressedPath::compress(&[]);
+        deep.bitmap[31] = 1;
+        let proof = SparseMerkleTreeProof::NotPresent {
+            siblings: deep,
+            leaf: None,
+        };
+        assert!(proof
+            .check("0", None::<&str>, &<SparseMerkleTree>::new().commit())
+            .is_none());
+    }
+
+    #[quickcheck]
+    fn hash_smt_multiproof_quickcheck(
+        entries: Vec<(u8, String)>,
+        queries: Vec<u8>,
+    ) {
+        let smt = entries
+            .iter()
+            .fold(<SparseMerkleTree>::new(), |smt, (k, v)| {
+                smt.insert(k.to_string(), v.clone())
+            });
+        let keys: Vec<String> = queries.iter().map(|k| k.to_string()).collect();
+
+        let (results, proof) = smt.get_many(&keys);
+        for (k, res) in keys.iter().zip(results.iter()) {
+            assert_eq!(*res, smt.get(k.clone()).0);
+        }
+        SparseMerkleTree::check_multiproof(
+            &keys,
+            &results,
+            &proof,
+            &smt.commit(),
+        )
+        .unwrap();
+
+        // flipping any result must be rejected
+        for i in 0..results.len() {
+            let mut forged = results.clone();
+            forged[i] = match &forged[i] {
+                Some(_) => None,
+                None => Some("".to_string()),
+            };
+            assert!(SparseMerkleTree::check_multiproof(
+                &keys,
+                &forged,
+                &proof,
+                &smt.commit(),
+            )
+            .is_none());
+        }
+    }
+
+    #[test]
+    fn hash_smt_multiproof_shares_nodes() {
+        let smt = (0..1000).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), "".to_string())
+        });
+        let keys: Vec<String> = (0..50).map(|i| (i * 3).to_string()).collect();
+        let (results, proof) = smt.get_many(&keys);
+        proof.check(&keys, &results, &smt.commit()).unwrap();
+
+        let multi_digests = proof
+            .nodes()
+            .iter()
+            .filter(|n| matches!(n, MultiProofNode::Hash(_)))
+            .count();
+        let single_digests: usize = keys
+            .iter()
+            .map(|k| smt.get(k.clone()).1.siblings().len())
+            .sum();
+        assert!(multi_digests < single_digests);
+
+        // a key whose path isn't covered by the proof can't be checked
+        let mut more_keys = keys.clone();
+        more_keys.push("1".to_string());
+        let mut more_results = results.clone();
+        more_results.push(smt.get("1".to_string()).0);
+        assert!(proof
+            .check(&more_keys, &more_results, &smt.commit())
+            .is_none());
+
+        // an empty proof only authenticates the empty tree
+        let (results, proof) = <SparseMerkleTree>::new().get_many(&keys);
+        assert!(results.iter().all(Option::is_none));
+        proof
+            .check(&keys, &results, &<SparseMerkleTree>::new().commit())
+            .unwrap();
+        assert!(proof.check(&keys, &results, &smt.commit()).is_none());
+    }
+
+    fn batch_ops(ops: &[InsertGetRemoveOp]) -> Vec<BatchOp> {
+        ops.iter()
+            .filter_map(|op| match op.clone() {
+                InsertGetRemoveOp::Insert(k, v) => Some(BatchOp::Insert(k, v)),
+                InsertGetRemoveOp::Get(_) => None,
+                InsertGetRemoveOp::Remove(k) => Some(BatchOp::Remove(k)),
+            })
+            .collect()
+    }
+
+    #[quickcheck]
+    fn hash_smt_apply_batch_quickcheck(
+        before: Vec<InsertGetRemoveOp>,
+        batch: Vec<InsertGetRemoveOp>,
+    ) {
+        let smt = batch_ops(&before).into_iter().fold(
+            <SparseMerkleTree>::new(),
+            |smt, op| match op {
+                BatchOp::Insert(k, v) => smt.insert(k, v),
+                BatchOp::Remove(k) => smt.remove(k),
+            },
+        );
+
+        let ops = batch_ops(&batch);
+        let batched = smt.clone().apply_batch(ops.clone());
+        let sequential = ops.into_iter().fold(smt, |smt, op| match op {
+            BatchOp::Insert(k, v) => smt.insert(k, v),
+            BatchOp::Remove(k) => smt.remove(k),
+        });
+
+        assert_eq!(batched.commit(), sequential.commit());
+        assert_eq!(batched.len(), sequential.len());
+        assert!(batched.iter().eq(sequential.iter()));
+        for op in batch {
+            let (InsertGetRemoveOp::Insert(k, _)
+            | InsertGetRemoveOp::Get(k)
+            | InsertGetRemoveOp::Remove(k)) = op;
+            let (val, proof) = batched.get(k.clone());
+            assert_eq!(val, sequential.get(k.clone()).0);
+            proof.check(&k, val.as_deref(), &batched.commit()).unwrap();
+        }
+    }
+
+    #[quickcheck]
+    fn hash_smt_build_parallel_quickcheck(
+        entries: Vec<(String, String)>,
+        threads: u8,
+    ) {
+        let sequential = entries
+            .iter()
+            .cloned()
+            .fold(<SparseMerkleTree>::new(), |smt, (k, v)| smt.insert(k, v));
+
+        let built =
+            <SparseMerkleTree>::build_parallel(entries, threads as usize % 9);
+        assert_eq!(built.commit(), sequential.commit());
+        assert_eq!(built.len(), sequential.len());
+        assert!(built.iter().eq(sequential.iter()));
+    }
+
+    #[test]
+    fn hash_smt_build_parallel_test_cases() {
+        let entries: Vec<_> = (0..5000)
+            .map(|i| (i.to_string(), format!("v{}", i)))
+            .chain((0..100).map(|i| (i.to_string(), "again".to_string())))
+            .collect();
+        let sequential = <SparseMerkleTree>::new().apply_batch(
+            entries
+                .iter()
+                .cloned()
+                .map(|(k, v)| BatchOp::Insert(k, v))
+                .collect(),
+        );
+
+        for threads in [0, 1, 2, 3, 8, 64, usize::MAX].iter() {
+            let built =
+                <SparseMerkleTree>::build_parallel(entries.clone(), *threads);
+            assert_eq!(built.commit(), sequential.commit());
+            assert_eq!(built.len(), 5000);
+        }
+        let built = <SparseMerkleTree>::build_parallel(entries, 4);
+        assert_eq!(built.get("7".to_string()).0, Some("again".to_string()));
+
+        // more threads than entries leaves most buckets empty
+        let one = <SparseMerkleTree>::build_parallel(
+            vec![("0".to_string(), "".to_string())],
+            16,
+        );
+        assert_eq!(
+            one.commit(),
+            <SparseMerkleTree>::new()
+                .insert("0".to_string(), "".to_string())
+                .commit()
+        );
+        assert_eq!(
+            <SparseMerkleTree>::build_parallel(vec![], 4).commit(),
+            <SparseMerkleTree>::new().commit()
+        );
+    }
+
+    #[test]
+    fn hash_smt_apply_batch_test_cases() {
+        use BatchOp::*;
+
+        let smt = <SparseMerkleTree>::new().apply_batch(
+            (0..100)
+                .map(|i| Insert(i.to_string(), i.to_string()))
+                .collect(),
+        );
+        let sequential = (0..100).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), i.to_string())
+        });
+        assert_eq!(smt.commit(), sequential.commit());
+
+        // later ops on the same key win
+        let smt = smt.apply_batch(vec![
+            Remove("1".to_string()),
+            Insert("1".to_string(), "a".to_string()),
+            Insert("2".to_string(), "b".to_string()),
+            Remove("2".to_string()),
+        ]);
+        assert_eq!(smt.get("1".to_string()).0, Some("a".to_string()));
+        assert_eq!(smt.get("2".to_string()).0, None);
+        assert_eq!(smt.len(), 99);
+
+        // removing keys which aren't there or inserting values which are
+        // already there changes nothing
+        let unchanged = smt.clone().apply_batch(vec![
+            Remove("2".to_string()),
+            Remove("x".to_string()),
+            Insert("1".to_string(), "a".to_string()),
+            Insert("3".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(unchanged.commit(), smt.commit());
+        assert_eq!(unchanged.len(), 99);
+
+        // removing everything collapses back to the empty tree
+        let smt =
+            smt.apply_batch((0..100).map(|i| Remove(i.to_string())).collect());
+        assert_eq!(smt.commit(), <SparseMerkleTree>::new().commit());
+    }
+
+    #[test]
+    fn hash_smt_versions_share_nodes() {
+        let v1 = (0..100).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), "".to_string())
+        });
+        let v2 = v1.clone().insert("0".to_string(), "a".to_string());
+        let v3 = v2.clone().remove("1".to_string());
+
+        // old versions are untouched
+        assert_eq!(v1.get("0".to_string()).0, Some("".to_string()));
+        assert_eq!(v2.get("0".to_string()).0, Some("a".to_string()));
+        assert_eq!(v2.get("1".to_string()).0, Some("".to_string()));
+        assert_eq!(v3.get("1".to_string()).0, None);
+        assert_ne!(v1.commit(), v2.commit());
+
+        // the subtree off the path of "0" is shared, the one on it is not
+        let h_k = KeyPath::from(smt_util::hash_key::<_, Sha256>("0"));
+        match (v1.root(), v2.root()) {
+            (
+                Node::Branch {
+                    left: l1,
+                    right: r1,
+                    ..
+                },
+                Node::Branch {
+                    left: l2,
+                    right: r2,
+                    ..
+                },
+            ) => {
+                let (same, changed) = if !h_k.bit(0) {
+                    ((r1, r2), (l1, l2))
+                } else {
+                    ((l1, l2), (r1, r2))
+                };
+                assert!(Arc::ptr_eq(same.0, same.1));
+                assert!(!Arc::ptr_eq(changed.0, changed.1));
+            }
+            _ => panic!("expected branches at the root"),
+        }
+    }
+
+    #[quickcheck]
+    fn hash_smt_update_witness_quickcheck(
+        before: Vec<InsertGetRemoveOp>,
+        batch: Vec<InsertGetRemoveOp>,
+    ) {
+        let smt = <SparseMerkleTree>::new().apply_batch(batch_ops(&before));
+        let ops = batch_ops(&batch);
+
+        let witness = smt.prove_update(&ops);
+        let new_root = smt.clone().apply_batch(ops.clone()).commit();
+        assert_eq!(witness.check_update(&smt.commit(), &ops), Some(new_root));
+    }
+
+    #[test]
+    fn hash_smt_update_witness_test_cases() {
+        use BatchOp::*;
+
+        let smt = (0..20).fold(<SparseMerkleTree>::new(), |smt, i| {
+            smt.insert(i.to_string(), "".to_string())
+        });
+        let ops = vec![
+            Remove("3".to_string()),
+            Insert("30".to_string(), "a".to_string()),
+            Insert("4".to_string(), "b".to_string()),
+        ];
+        let witness = smt.prove_update(&ops);
+        let new_root = smt.clone().apply_batch(ops.clone()).commit();
+        assert_eq!(witness.check_update(&smt.commit(), &ops), Some(new_root));
+
+        // the witness only authenticates against the old root
+        let other = smt.clone().insert("100".to_string(), "".to_string());
+        assert_eq!(witness.check_update(&other.commit(), &ops), None);
+
+        // ops on keys the witness doesn't cover are rejected
+        let mut more_ops = ops.clone();
+        more_ops.push(Remove("5".to_string()));
+        assert_eq!(witness.check_update(&smt.commit(), &more_ops), None);
+
+        // hiding a sibling leaf as a digest is rejected when removing its
+        // neighbour, since the leaf would have to move up
+        let smt = <SparseMerkleTree>::new()
+            .insert("0".to_string(), "".to_string())
+            .insert("1".to_string(), "".to_string());
+        let ops = vec![Remove("0".to_string())];
+        let witness = smt.prove_update(&ops);
+        assert_eq!(
+            witness.check_update(&smt.commit(), &ops),
+            Some(smt.clone().remove("0".to_string()).commit())
+        );
+
+        let hidden = SparseMerkleTreeMultiProof {
+            nodes: witness
+                .nodes()
+                .iter()
+                .map(|node| match node {
+                    MultiProofNode::Leaf(key, h_v)
+                        if *key != smt_util::hash_key("0") =>
+                    {
+                        MultiProofNode::Hash(smt_util::hash_leaf(*key, *h_v))
+                    }
+                    node => node.clone(),
+                })
+                .collect(),
+        };
+        assert_ne!(hidden, witness);
+        assert_eq!(hidden.check_update(&smt.commit(), &ops), None);
+
+        let ops = vec![Remove("0".to_string())];
+        // an empty sibling given as its digest, or as a branch of two empty
+        // subtrees, still lets the remaining leaf move up. Two keys which
+        // share their first bit have an empty sibling at depth 1:
+        // Branch, Branch, Leaf, Leaf, Empty or Branch, Empty, Branch, ...
+        let smt = (1..)
+            .map(|i| {
+                <SparseMerkleTree>::new()
+                    .insert("0".to_string(), "".to_string())
+                    .insert(i.to_string(), "".to_string())
+            })
+            .find(|smt| {
+                let nodes = smt.prove_update(&ops).nodes;
+                nodes.len() == 5 && nodes.contains(&MultiProofNode::Empty)
+            })
+            .unwrap();
+        let witness = smt.prove_update(&ops);
+        let new_root = smt.clone().remove("0".to_string()).commit();
+        let empty_at_1 = EmptyHashes::<Sha256>::get().at(1);
+        let empty_at_2 = EmptyHashes::<Sha256>::get().at(2);
+        let forged_empties = [
+            vec![MultiProofNode::Hash(empty_at_1)],
+            vec![
+                MultiProofNode::Branch,
+                MultiProofNode::Hash(empty_at_2),
+                MultiProofNode::Empty,
+            ],
+        ];
+        for forged_empty in forged_empties.iter() {
+            let forged = SparseMerkleTreeMultiProof {
+                nodes: witness
+                    .nodes()
+                    .iter()
+                    .flat_map(|node| match node {
+                        MultiProofNode::Empty => forged_empty.clone(),
+                        node => vec![node.clone()],
+                    })
+                    .collect(),
+            };
+            assert_ne!(forged, witness);
+            assert_eq!(
+                forged.check_update(&smt.commit(), &ops),
+                Some(new_root)
+            );
+        }
+    }
 }
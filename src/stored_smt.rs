@@ -0,0 +1,157 @@
+use crate::common::{Digest, Encode, Hasher, Sha256};
+use crate::node_store::{NodeStore, StoredNode};
+use crate::sparse_merkle_tree::SparseMerkleTree;
+use std::io;
+
+/**
+ * A `StoredSparseMerkleTree` is a `SparseMerkleTree` whose nodes are read
+ * from and written to a `NodeStore` which addresses them by digest, such as
+ * a `FileNodeStore`, instead of being held in memory.
+ *
+ * Nodes are never overwritten, so the tree is just its root digest:
+ * reopening a store with any root it has committed serves lookups and
+ * proofs for that version. Commitments and proofs are the same as for the
+ * in-memory `SparseMerkleTree` holding the same entries, since both run the
+ * same algorithm over their store.
+ */
+pub type StoredSparseMerkleTree<S, K = String, V = String, H = Sha256> =
+    SparseMerkleTree<K, V, H, S>;
+
+impl<K, V, H, S> SparseMerkleTree<K, V, H, S>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+    S: NodeStore<K, V, H, Ref = Digest<H>>,
+{
+    /// Reopen the tree committed to by `root` from `store`.
+    pub fn open(store: S, root: Digest<H>) -> io::Result<Self> {
+        let len = match store.get(&root, 0)? {
+            StoredNode::Empty => 0,
+            StoredNode::Leaf { .. } => 1,
+            StoredNode::Branch { len, .. } => len,
+        };
+
+        let mut tree = Self::with_store(store);
+        tree.set_root(root, len);
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_trait::AuthenticatedKV;
+    use crate::node_store::tests::TempPath;
+    use crate::node_store::FileNodeStore;
+    use crate::sorted_kv::tests::InsertGetRemoveOp;
+    use crate::sparse_merkle_tree::BatchOp;
+
+    #[quickcheck]
+    fn stored_smt_matches_in_memory(
+        ops: Vec<InsertGetRemoveOp>,
+        batch: Vec<InsertGetRemoveOp>,
+    ) {
+        let path = TempPath::new("stored-smt-quickcheck");
+        let store: FileNodeStore = FileNodeStore::open(&path.0).unwrap();
+        let mut stored = StoredSparseMerkleTree::with_store(store);
+        let mut smt = SparseMerkleTree::new();
+
+        for op in ops {
+            match op {
+                InsertGetRemoveOp::Insert(k, v) => {
+                    stored.try_insert(k.clone(), v.clone()).unwrap();
+                    smt = smt.insert(k, v);
+                }
+                InsertGetRemoveOp::Get(k) => {
+                    let (val, proof) = stored.try_get(&k).unwrap();
+                    assert_eq!(
+                        (val.clone(), proof.clone()),
+                        smt.get(k.clone())
+                    );
+                    proof.check(&k, val.as_ref(), &stored.commit()).unwrap();
+                }
+                InsertGetRemoveOp::Remove(k) => {
+                    stored.try_remove(&k).unwrap();
+                    smt = smt.remove(k);
+                }
+            }
+            assert_eq!(stored.commit(), smt.commit());
+            assert_eq!(stored.len(), smt.len());
+        }
+
+        let entries: Vec<_> =
+            stored.try_iter().collect::<io::Result<_>>().unwrap();
+        let expected: Vec<_> =
+            smt.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(entries, expected);
+
+        let mut keys: Vec<String> =
+            entries.into_iter().map(|(k, _)| k).collect();
+        keys.push("missing".to_string());
+        assert_eq!(stored.try_get_many(&keys).unwrap(), smt.get_many(&keys));
+
+        let ops: Vec<BatchOp> = batch
+            .into_iter()
+            .map(|op| match op {
+                InsertGetRemoveOp::Insert(k, v) => BatchOp::Insert(k, v),
+                InsertGetRemoveOp::Get(k) | InsertGetRemoveOp::Remove(k) => {
+                    BatchOp::Remove(k)
+                }
+            })
+            .collect();
+        assert_eq!(
+            stored.try_prove_update(&ops).unwrap(),
+            smt.prove_update(&ops)
+        );
+        stored.try_apply_batch(ops.clone()).unwrap();
+        smt = smt.apply_batch(ops);
+        assert_eq!(stored.commit(), smt.commit());
+        assert_eq!(stored.len(), smt.len());
+    }
+
+    #[test]
+    fn stored_smt_reopen_from_file() {
+        let path = TempPath::new("stored-smt");
+
+        let store: FileNodeStore = FileNodeStore::open(&path.0).unwrap();
+        let mut tree = StoredSparseMerkleTree::with_store(store);
+        for i in 0..50 {
+            tree.try_insert(i.to_string(), format!("v{}", i)).unwrap();
+        }
+        let old_root = tree.commit();
+        for i in 0..25 {
+            tree.try_remove(&i.to_string()).unwrap();
+        }
+        let root = tree.commit();
+        tree.store_mut().sync().unwrap();
+        drop(tree);
+
+        // every committed root can be reopened
+        let store: FileNodeStore = FileNodeStore::open(&path.0).unwrap();
+        let tree = StoredSparseMerkleTree::open(store, root).unwrap();
+        assert_eq!(tree.len(), 25);
+        for i in 0..50 {
+            let key = i.to_string();
+            let (val, proof) = tree.try_get(&key).unwrap();
+            let expected = format!("v{}", i);
+            assert_eq!(val, Some(expected).filter(|_| i >= 25));
+            proof.check(&key, val.as_ref(), &root).unwrap();
+        }
+
+        let tree =
+            StoredSparseMerkleTree::open(tree.into_store(), old_root).unwrap();
+        assert_eq!(tree.len(), 50);
+        let (val, proof) = tree.try_get(&"0".to_string()).unwrap();
+        assert_eq!(val.as_deref(), Some("v0"));
+        proof.check("0", val.as_ref(), &old_root).unwrap();
+
+        // a root which was never committed can't be opened
+        let unknown = SparseMerkleTree::new()
+            .insert("0".to_string(), "".to_string())
+            .commit();
+        let err = StoredSparseMerkleTree::open(tree.into_store(), unknown)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::common::{Digest, Encode, Hasher, Sha256};
+use crate::kv_trait::AuthenticatedKV;
+use crate::sparse_merkle_tree::{
+    BatchOp, SparseMerkleTree, SparseMerkleTreeProof,
+};
+use std::collections::BTreeMap;
+
+/// The number of a committed version, counting up from 0.
+pub type Version = u64;
+
+/**
+ * A `VersionedSparseMerkleTree` is a `SparseMerkleTree` which keeps every
+ * committed version around, so lookups can be proven against any
+ * historical root.
+ *
+ * Updates go to a working tree, and `commit()` records it as the next
+ * version. Versions share every node which didn't change between them, so
+ * each one only costs the nodes on the paths it updated.
+ */
+#[derive(Debug, Clone)]
+pub struct VersionedSparseMerkleTree<K = String, V = String, H = Sha256>
+where
+    K: Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    working: SparseMerkleTree<K, V, H>,
+    versions: BTreeMap<Version, SparseMerkleTree<K, V, H>>,
+    next_version: Version,
+}
+
+impl<K, V, H> VersionedSparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    pub fn new() -> Self {
+        VersionedSparseMerkleTree {
+            working: SparseMerkleTree::new(),
+            versions: BTreeMap::new(),
+            next_version: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.working = std::mem::take(&mut self.working).insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.working = std::mem::take(&mut self.working).remove(key);
+    }
+
+    pub fn apply_batch(&mut self, ops: Vec<BatchOp<K, V>>) {
+        self.working = std::mem::take(&mut self.working).apply_batch(ops);
+    }
+
+    /// Record the working tree as a new version, returning its number and
+    /// root.
+    pub fn commit(&mut self) -> (Version, Digest<H>) {
+        let version = self.next_version;
+        self.next_version += 1;
+
+        let root = self.working.commit();
+        self.versions.insert(version, self.working.clone());
+
+        (version, root)
+    }
+
+    /// The most recent version which hasn't been pruned.
+    pub fn latest_version(&self) -> Option<Version> {
+        self.versions.keys().next_back().copied()
+    }
+
+    /// The versions which are still available, oldest first.
+    pub fn versions(&self) -> impl Iterator<Item = Version> + '_ {
+        self.versions.keys().copied()
+    }
+
+    /// The tree as of `version`, or `None` if it was never committed or
+    /// has been pruned.
+    pub fn tree_at(
+        &self,
+        version: Version,
+    ) -> Option<&SparseMerkleTree<K, V, H>> {
+        self.versions.get(&version)
+    }
+
+    pub fn root_at(&self, version: Version) -> Option<Digest<H>> {
+        self.tree_at(version).map(SparseMerkleTree::commit)
+    }
+
+    /// Look up `key` as of `version`, with a proof against `root_at(version)`.
+    pub fn get_at(
+        &self,
+        version: Version,
+        key: K,
+    ) -> Option<(Option<V>, SparseMerkleTreeProof<H>)> {
+        self.tree_at(version).map(|tree| tree.get(key))
+    }
+
+    /// Drop every version older than `horizon`. Nodes which are only
+    /// reachable from those versions are freed. Returns the number of
+    /// versions dropped.
+    pub fn prune(&mut self, horizon: Version) -> usize {
+        let retained = self.versions.split_off(&horizon);
+        let pruned = self.versions.len();
+        self.versions = retained;
+        pruned
+    }
+}
+
+impl<K, V, H> Default for VersionedSparseMerkleTree<K, V, H>
+where
+    K: Encode + Clone,
+    V: Encode + Clone,
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Sha512_256;
+    use crate::sorted_kv::tests::binary_entries;
+
+    #[test]
+    fn versioned_smt_get_at() {
+        let mut tree = <VersionedSparseMerkleTree>::new();
+        assert_eq!(tree.latest_version(), None);
+
+        tree.insert("0".to_string(), "a".to_string());
+        tree.insert("1".to_string(), "b".to_string());
+        let (v0, root0) = tree.commit();
+
+        tree.insert("0".to_string(), "c".to_string());
+        tree.remove("1".to_string());
+        let (v1, root1) = tree.commit();
+
+        // uncommitted updates aren't visible in any version
+        tree.insert("2".to_string(), "d".to_string());
+
+        assert_eq!((v0, v1), (0, 1));
+        assert_eq!(tree.latest_version(), Some(v1));
+        assert_ne!(root0, root1);
+
+        for (version, root, key, expected) in [
+            (v0, root0, "0", Some("a")),
+            (v0, root0, "1", Some("b")),
+            (v1, root1, "0", Some("c")),
+            (v1, root1, "1", None),
+            (v1, root1, "2", None),
+        ] {
+            assert_eq!(tree.root_at(version), Some(root));
+            let (val, proof) = tree.get_at(version, key.to_string()).unwrap();
+            assert_eq!(val.as_deref(), expected);
+            proof.check(key, expected, &root).unwrap();
+        }
+    }
+
+    #[test]
+    fn versioned_smt_prune() {
+        let mut tree = <VersionedSparseMerkleTree>::new();
+        for i in 0..10 {
+            tree.insert(i.to_string(), i.to_string());
+            tree.commit();
+        }
+
+        assert_eq!(tree.prune(7), 7);
+        assert_eq!(tree.versions().collect::<Vec<_>>(), vec![7, 8, 9]);
+        assert!(tree.get_at(6, "0".to_string()).is_none());
+
+        let (val, proof) = tree.get_at(7, "7".to_string()).unwrap();
+        assert_eq!(val, Some("7".to_string()));
+        proof
+            .check("7", val.as_deref(), &tree.root_at(7).unwrap())
+            .unwrap();
+        assert_eq!(tree.get_at(7, "8".to_string()).unwrap().0, None);
+
+        // version numbers keep counting after pruning
+        assert_eq!(tree.prune(100), 3);
+        assert_eq!(tree.commit().0, 10);
+    }
+
+    #[test]
+    fn versioned_smt_other_types() {
+        let mut tree =
+            VersionedSparseMerkleTree::<Vec<u8>, Vec<u8>, Sha512_256>::new();
+        for (k, v) in binary_entries() {
+            tree.insert(k, v);
+        }
+        let (v0, root0) = tree.commit();
+        tree.remove(vec![0xff]);
+        let (v1, _) = tree.commit();
+
+        let (val, proof) = tree.get_at(v0, vec![0xff]).unwrap();
+        assert_eq!(val, Some(vec![0x80, 0]));
+        proof.check(&[0xff][..], val.as_deref(), &root0).unwrap();
+        assert_eq!(tree.get_at(v1, vec![0xff]).unwrap().0, None);
+    }
+}